@@ -0,0 +1,82 @@
+//! Throughput of decoding a multi-megabyte evaluation result for a few sizes of
+//! `Protocol::with_read_buffer_size`. The server is replayed from memory by
+//! [`MockProtocol`], so this measures the reader thread and the decoder, not pipe
+//! latency.
+//!
+//! Run with `cargo bench --features test-util --bench read_buffer`.
+
+use std::time::{Duration, Instant};
+
+use rust_pkl::{
+    EvalOpts, Evaluator, Uri, Value,
+    mock::{self, MockProtocol},
+    protocol::Message,
+    server::{CreateEvaluatorResponse, EvaluateResponse},
+    value,
+};
+use serde::Serialize;
+
+const BUFFER_SIZES: [usize; 5] = [4 << 10, 16 << 10, 64 << 10, 256 << 10, 1 << 20];
+const ITEMS: usize = 100_000;
+const ROUNDS: u32 = 10;
+
+#[derive(Serialize)]
+struct Item {
+    id: usize,
+    name: String,
+    enabled: bool,
+}
+
+/// A `Listing` of [`ITEMS`] maps, as pkl encodes a module result.
+fn result() -> Vec<u8> {
+    let items: Vec<Item> = (0..ITEMS)
+        .map(|id| Item {
+            id,
+            name: format!("item-{id}"),
+            enabled: id % 2 == 0,
+        })
+        .collect();
+
+    rmp_serde::to_vec_named(&(0x5, items)).unwrap()
+}
+
+fn transcript(result: Vec<u8>) -> Vec<u8> {
+    let result = Value::Bytes(result);
+    let created = value!({ "requestId" => 0, "evaluatorId" => 1 });
+    let evaluated = value!({ "requestId" => 1, "evaluatorId" => 1, "result" => result });
+
+    let mut transcript = mock::message(CreateEvaluatorResponse::CODE, created).unwrap();
+    transcript.extend(mock::message(EvaluateResponse::CODE, evaluated).unwrap());
+    transcript
+}
+
+fn main() {
+    let transcript = transcript(result());
+    let mb = transcript.len() as f64 / (1 << 20) as f64;
+    println!("decoding {mb:.1} MiB, best of {ROUNDS} rounds");
+
+    for size in BUFFER_SIZES {
+        let mut best = Duration::MAX;
+
+        for _ in 0..ROUNDS {
+            let transcript = transcript.clone();
+
+            let start = Instant::now();
+            let (proto, _mock) = MockProtocol::new(transcript);
+            let mut evaluator = Evaluator::new(proto.with_read_buffer_size(size));
+            let value = evaluator
+                .eval(&EvalOpts::default(), Uri::default())
+                .unwrap();
+            best = best.min(start.elapsed());
+
+            assert!(matches!(value, Some(Value::Listing(items)) if items.len() == ITEMS));
+        }
+
+        let throughput = mb / best.as_secs_f64();
+        println!(
+            "{:>5} KiB buffer: {:>8.2?} ({throughput:.0} MiB/s)",
+            size >> 10,
+            best
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::instrument;
+
+use crate::{
+    async_protocol::AsyncProtocol,
+    client::{CloseEvaluator, EvaluateRequest, Uri},
+    errors::Error,
+    evaluator::{EvalOpts, EvaluatorSetup},
+    observer::LogEvent,
+    server::Value,
+};
+
+/// [`Evaluator`](crate::Evaluator) on tokio, evaluating through an
+/// [`AsyncProtocol`] so callers such as request handlers don't need
+/// `spawn_blocking`.
+pub struct AsyncEvaluator {
+    request_id: AtomicU64,
+    proto: AsyncProtocol,
+}
+
+impl AsyncEvaluator {
+    pub fn new(proto: AsyncProtocol) -> Self {
+        Self {
+            proto,
+            request_id: AtomicU64::new(0),
+        }
+    }
+
+    /// A request id no other request of this evaluator uses, so responses can be
+    /// matched to their requests. Wraps around on overflow, which is fine for our use
+    /// case.
+    fn gen_request_id(&self) -> u64 {
+        self.request_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Passes the `trace()` output and warnings of evaluated modules to `f`, like
+    /// [`Evaluator::on_log`](crate::Evaluator::on_log).
+    pub fn on_log(&mut self, f: impl FnMut(LogEvent) + Send + 'static) {
+        self.proto.on_log(f);
+    }
+
+    /// Whether the `pkl server` child can take more evaluations, see
+    /// [`AsyncProtocol::is_usable`].
+    pub(crate) fn is_usable(&mut self) -> bool {
+        self.proto.is_usable()
+    }
+
+    pub async fn close(self) -> Result<(), Error> {
+        self.proto.close().await
+    }
+
+    /// Evaluates the module at `uri`, like [`Evaluator::eval`](crate::Evaluator::eval).
+    #[instrument(skip(self, opts))]
+    pub async fn eval(&mut self, opts: &EvalOpts, uri: Uri) -> Result<Option<Value>, Error> {
+        let evaluator_id = self.create_evaluator(opts, &uri).await?;
+
+        let request = EvaluateRequest {
+            request_id: self.gen_request_id(),
+            evaluator_id,
+            module_uri: uri,
+            module_text: None,
+            expr: None,
+        };
+        let response = self.proto.evaluate_request(request).await;
+        let closed = self
+            .proto
+            .close_evaluator(CloseEvaluator { evaluator_id })
+            .await;
+
+        let value = opts.response_value(response?)?;
+        closed?;
+
+        Ok(value)
+    }
+
+    async fn create_evaluator(&mut self, opts: &EvalOpts, uri: &Uri) -> Result<i64, Error> {
+        let setup = EvaluatorSetup::new(opts, Some(uri))?;
+        let request_id = self.gen_request_id();
+
+        let response = self
+            .proto
+            .create_evaluator_request(setup.request(request_id))
+            .await?;
+        let evaluator_id = EvaluatorSetup::evaluator_id(response, request_id)?;
+        setup.register_readers(evaluator_id, self.proto.requests_mut());
+
+        Ok(evaluator_id)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        fs,
+        process::Stdio,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{
+        async_protocol::READ_CHUNK_SIZE,
+        mock::{self, CREATE_EVALUATE, EVALUATE_WITH_LOGS},
+        observer::LogLevel,
+        protocol::Message,
+        server::{CreateEvaluatorResponse, EvaluateResponse},
+        value,
+    };
+
+    /// An evaluator whose server prints `transcript` and waits for stdin to close.
+    fn replaying(name: &str, transcript: &[u8]) -> AsyncEvaluator {
+        let path =
+            std::env::temp_dir().join(format!("rust-pkl-{name}-{}.msgpack", std::process::id()));
+        fs::write(&path, transcript).unwrap();
+
+        let child = tokio::process::Command::new("sh")
+            .args(["-c", r#"cat "$0"; rm "$0"; cat >/dev/null"#])
+            .arg(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+
+        AsyncEvaluator::new(AsyncProtocol::from_child(child).unwrap())
+    }
+
+    #[tokio::test]
+    async fn eval_returns_the_module() {
+        let mut evaluator = replaying("async-eval", CREATE_EVALUATE);
+
+        let value = evaluator.eval(&EvalOpts::default(), Uri::default()).await;
+
+        assert!(matches!(value, Ok(Some(_))), "{value:?}");
+    }
+
+    #[tokio::test]
+    async fn results_longer_than_a_read_are_decoded() {
+        let text = "x".repeat(3 * READ_CHUNK_SIZE);
+        let created = value!({ "requestId" => 0, "evaluatorId" => 1 });
+        let result = Value::Bytes(rmp_serde::to_vec(&text).unwrap());
+        let evaluated = value!({ "requestId" => 1, "evaluatorId" => 1, "result" => result });
+        let mut transcript = mock::message(CreateEvaluatorResponse::CODE, created).unwrap();
+        transcript.extend(mock::message(EvaluateResponse::CODE, evaluated).unwrap());
+        let mut evaluator = replaying("async-long", &transcript);
+
+        let value = evaluator.eval(&EvalOpts::default(), Uri::default()).await;
+
+        assert_eq!(value.unwrap(), Some(Value::String(text)));
+    }
+
+    #[tokio::test]
+    async fn logs_reach_on_log() {
+        let mut evaluator = replaying("async-logs", EVALUATE_WITH_LOGS);
+        let levels = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&levels);
+        evaluator.on_log(move |event| seen.lock().unwrap().push(event.level));
+
+        evaluator
+            .eval(&EvalOpts::default(), Uri::default())
+            .await
+            .unwrap();
+
+        assert_eq!(*levels.lock().unwrap(), [LogLevel::Trace, LogLevel::Warn]);
+    }
+}
@@ -0,0 +1,305 @@
+use std::{io, sync::Arc};
+
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::{Child, ChildStdin, ChildStdout},
+};
+use tracing::instrument;
+
+use crate::{
+    client::{CloseEvaluator, CreateEvaluatorRequest, EvaluateRequest},
+    decoder::{Decoder, MessageScanner},
+    errors::Error,
+    observer::LogEvent,
+    protocol::{
+        self, DuplicateResponsePolicy, Message, PendingResponses, Protocol, ServerRequests,
+    },
+    reader::{ModuleReader, ResourceReader},
+    server::{CreateEvaluatorResponse, EvaluateResponse, Response},
+};
+
+/// Size of the reads from the server's stdout.
+pub(crate) const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// [`Protocol`] on tokio: the same messages over the pipes of a `pkl server` child
+/// process, sent and received without blocking the runtime.
+///
+/// Answers to requests are read in order, so one `AsyncProtocol` evaluates one
+/// module at a time; run several for concurrent evaluations. The child is killed
+/// when the protocol is dropped.
+pub struct AsyncProtocol {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    /// Bytes read from stdout that don't form a complete message yet.
+    buf: Vec<u8>,
+    /// How far into `buf` the next message has been found to reach.
+    scanner: MessageScanner,
+    requests: ServerRequests,
+    pending: PendingResponses,
+    duplicate_policy: DuplicateResponsePolicy,
+    /// Set when the server's output couldn't be split into messages, after which
+    /// nothing more can be read from it.
+    corrupted: bool,
+}
+
+impl AsyncProtocol {
+    /// Spawns `pkl server` like [`Protocol::new`]. Must be called from within a tokio
+    /// runtime; use [`Protocol::builder`] and
+    /// [`ProtocolBuilder::spawn_async`](crate::protocol::ProtocolBuilder::spawn_async)
+    /// to configure the process.
+    pub fn new() -> Result<Self, Error> {
+        Protocol::builder().spawn_async()
+    }
+
+    pub(crate) fn from_child(mut child: Child) -> Result<Self, Error> {
+        let stdin = child.stdin.take().ok_or(Error::Pipe)?;
+        let stdout = child.stdout.take().ok_or(Error::Pipe)?;
+
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+            buf: Vec::new(),
+            scanner: MessageScanner::default(),
+            requests: ServerRequests::default(),
+            pending: PendingResponses::default(),
+            duplicate_policy: DuplicateResponsePolicy::default(),
+            corrupted: false,
+        })
+    }
+
+    /// Process id of the `pkl server` child. `None` once the child has exited.
+    pub fn pid(&mut self) -> Option<u32> {
+        match self.child.try_wait() {
+            Ok(None) => self.child.id(),
+            _ => None,
+        }
+    }
+
+    /// What to do with responses to requests that aren't in flight, like
+    /// [`Protocol::set_duplicate_response_policy`].
+    pub fn set_duplicate_response_policy(&mut self, policy: DuplicateResponsePolicy) {
+        self.duplicate_policy = policy;
+    }
+
+    /// Whether the child is still running and its output can still be read, i.e.
+    /// the protocol can take more requests.
+    pub fn is_usable(&mut self) -> bool {
+        !self.corrupted && self.pid().is_some()
+    }
+
+    /// Passes every `Log` message to `f` instead of emitting it through `tracing`.
+    pub fn on_log(&mut self, f: impl FnMut(LogEvent) + Send + 'static) {
+        self.requests.on_log(f);
+    }
+
+    #[instrument(skip_all, fields(id = request.request_id))]
+    pub async fn create_evaluator_request(
+        &mut self,
+        request: CreateEvaluatorRequest<'_>,
+    ) -> Result<CreateEvaluatorResponse, Error> {
+        let request_id = request.request_id;
+        self.pending.expect(request_id);
+        self.send(request).await?;
+        self.recv_until_response(request_id).await
+    }
+
+    #[instrument(skip_all, fields(id = request.request_id))]
+    pub async fn evaluate_request(
+        &mut self,
+        request: EvaluateRequest<'_>,
+    ) -> Result<EvaluateResponse, Error> {
+        let request_id = request.request_id;
+        self.pending.expect(request_id);
+        self.send(request).await?;
+        self.recv_until_response(request_id).await
+    }
+
+    #[instrument(skip_all, fields(id = request.evaluator_id))]
+    pub async fn close_evaluator(&mut self, request: CloseEvaluator) -> Result<(), Error> {
+        self.requests.unregister(request.evaluator_id);
+        self.send(request).await
+    }
+
+    /// Serves `ReadResource` requests for `evaluator_id` from `readers`, like
+    /// [`Protocol::register_resource_readers`].
+    pub fn register_resource_readers(
+        &mut self,
+        evaluator_id: i64,
+        readers: Vec<Arc<dyn ResourceReader>>,
+    ) {
+        self.requests
+            .register_resource_readers(evaluator_id, readers);
+    }
+
+    /// Serves `ReadModule` requests for `evaluator_id` from `readers`, like
+    /// [`Protocol::register_module_readers`].
+    pub fn register_module_readers(
+        &mut self,
+        evaluator_id: i64,
+        readers: Vec<Arc<dyn ModuleReader>>,
+    ) {
+        self.requests.register_module_readers(evaluator_id, readers);
+    }
+
+    pub(crate) fn requests_mut(&mut self) -> &mut ServerRequests {
+        &mut self.requests
+    }
+
+    /// Reads messages until the response of type `T` to `request_id` arrives, answering
+    /// any logs and server requests that are interleaved before it and keeping
+    /// answers to other requests in flight for their own waiters.
+    async fn recv_until_response<T>(&mut self, request_id: u64) -> Result<T, Error>
+    where
+        T: Message + DeserializeOwned,
+        T: TryFrom<Response, Error = Error>,
+    {
+        if let Some(response) = self.pending.take(request_id) {
+            return response.try_into();
+        }
+
+        loop {
+            let response = self.recv_any().await?;
+
+            let Some(id) = response.request_id() else {
+                if let Some(frame) = self.requests.answer(response)? {
+                    self.write_frame(&frame).await?;
+                }
+                continue;
+            };
+
+            if id == request_id {
+                self.pending.done(id);
+                return response.try_into();
+            }
+
+            if !self.pending.buffer(id, response) {
+                self.duplicate_policy.apply(id)?;
+            }
+        }
+    }
+
+    async fn send<M: Message + Serialize>(&mut self, message: M) -> Result<(), Error> {
+        let frame = protocol::encode(message)?;
+        self.write_frame(&frame).await
+    }
+
+    /// Frames `body` as a message with `code` and sends it, like
+    /// [`Protocol::send_raw`].
+    pub async fn send_raw(&mut self, code: u64, body: impl Serialize) -> Result<(), Error> {
+        let frame = protocol::encode_raw(code, body)?;
+        self.write_frame(&frame).await
+    }
+
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.stdin.write_all(frame).await?;
+        self.stdin.flush().await?;
+
+        Ok(())
+    }
+
+    /// Reads the next message from the server, whatever it is, without handling it.
+    pub async fn recv_any(&mut self) -> Result<Response, Error> {
+        loop {
+            let len = self
+                .scanner
+                .message_len(&self.buf)
+                .inspect_err(|_| self.corrupted = true)?;
+
+            if let Some(len) = len {
+                let response = Decoder::new(&self.buf[..len]).decode_response();
+                self.buf.drain(..len);
+
+                return response;
+            }
+
+            self.buf.reserve(READ_CHUNK_SIZE);
+
+            if self.stdout.read_buf(&mut self.buf).await? == 0 {
+                return Err(Error::IO(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    /// Kills the `pkl server` process and waits for it to exit.
+    pub async fn close(mut self) -> Result<(), Error> {
+        let _ = self.child.start_kill();
+        self.child.wait().await?;
+
+        Ok(())
+    }
+}
+
+// The server is played by `sh`, which prints a transcript and then drains stdin.
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, process::Stdio};
+
+    use super::*;
+    use crate::{client::Uri, mock, server::Value, value};
+
+    fn evaluated(request_id: u64) -> Vec<u8> {
+        let result = Value::Bytes(vec![0x01]);
+        let evaluated = value!({
+            "requestId" => request_id,
+            "evaluatorId" => 1,
+            "result" => result,
+        });
+        mock::message(EvaluateResponse::CODE, evaluated).unwrap()
+    }
+
+    /// A protocol whose server prints the answer to request 0 twice, then the answer
+    /// to request 1.
+    fn replaying_duplicate(name: &str) -> AsyncProtocol {
+        let transcript =
+            std::env::temp_dir().join(format!("rust-pkl-{name}-{}.msgpack", std::process::id()));
+        let mut bytes = evaluated(0);
+        bytes.extend(evaluated(0));
+        bytes.extend(evaluated(1));
+        fs::write(&transcript, bytes).unwrap();
+
+        let child = tokio::process::Command::new("sh")
+            .args(["-c", r#"cat "$0"; rm "$0"; cat >/dev/null"#])
+            .arg(&transcript)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+
+        AsyncProtocol::from_child(child).unwrap()
+    }
+
+    fn request(request_id: u64) -> EvaluateRequest<'static> {
+        EvaluateRequest {
+            request_id,
+            evaluator_id: 1,
+            module_uri: Uri::default(),
+            module_text: None,
+            expr: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_responses_fail_by_default() {
+        let mut proto = replaying_duplicate("duplicate-error");
+
+        proto.evaluate_request(request(0)).await.unwrap();
+        let err = proto.evaluate_request(request(1)).await.unwrap_err();
+
+        assert!(matches!(err, Error::DuplicateResponse { request_id: 0 }));
+    }
+
+    #[tokio::test]
+    async fn duplicate_responses_can_be_ignored() {
+        let mut proto = replaying_duplicate("duplicate-ignore");
+        proto.set_duplicate_response_policy(DuplicateResponsePolicy::Ignore);
+
+        proto.evaluate_request(request(0)).await.unwrap();
+        let response = proto.evaluate_request(request(1)).await.unwrap();
+
+        assert_eq!(response.request_id, 1);
+    }
+}
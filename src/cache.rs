@@ -0,0 +1,73 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use tracing::instrument;
+
+use crate::{
+    client::Uri,
+    errors::Error,
+    evaluator::{EvalOpts, Evaluator},
+    server::Value,
+};
+
+struct CacheEntry {
+    mtime: SystemTime,
+    value: Option<Value>,
+}
+
+/// Caches decoded results of file modules, keyed by the module's path and the
+/// options it was evaluated with.
+///
+/// An entry is reused for as long as the module file's modification time is
+/// unchanged. Only the target file is tracked, so edits to imported modules are
+/// not noticed; call [`EvalCache::invalidate`] or [`EvalCache::clear`] for those.
+#[derive(Default)]
+pub struct EvalCache {
+    entries: HashMap<(PathBuf, u64), CacheEntry>,
+}
+
+impl EvalCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[instrument(skip(self, evaluator, opts, path), fields(path = ?path.as_ref()))]
+    pub fn eval(
+        &mut self,
+        evaluator: &mut Evaluator,
+        opts: &EvalOpts,
+        path: impl AsRef<Path>,
+    ) -> Result<Option<&Value>, Error> {
+        let path = fs::canonicalize(path)?;
+        let mtime = fs::metadata(&path)?.modified()?;
+
+        let entry = match self.entries.entry((path, opts.create_options_hash())) {
+            Entry::Occupied(entry) if entry.get().mtime == mtime => entry.into_mut(),
+            Entry::Occupied(mut entry) => {
+                let value = evaluator.eval(opts, Uri::File(entry.key().0.clone()))?;
+                entry.insert(CacheEntry { mtime, value });
+                entry.into_mut()
+            }
+            Entry::Vacant(entry) => {
+                let value = evaluator.eval(opts, Uri::File(entry.key().0.clone()))?;
+                entry.insert(CacheEntry { mtime, value })
+            }
+        };
+
+        Ok(entry.value.as_ref())
+    }
+
+    /// Drops every cached result for `path`, regardless of options.
+    pub fn invalidate(&mut self, path: impl AsRef<Path>) {
+        let path = fs::canonicalize(&path).unwrap_or_else(|_| path.as_ref().to_path_buf());
+        self.entries.retain(|(cached, _), _| *cached != path);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -3,13 +3,15 @@ use serde_with::skip_serializing_none;
 use std::{
     collections::HashMap,
     fmt::{self, Display},
-    fs,
+    fs, io,
     path::{Path, PathBuf},
 };
 
-use crate::{errors::ProjectError, protocol::Message};
+#[cfg(feature = "json")]
+use crate::errors::ProjectError;
+use crate::protocol::Message;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Uri {
     File(PathBuf),
     Url(String),
@@ -27,10 +29,25 @@ impl<'de> Deserialize<'de> for Uri {
         D: serde::Deserializer<'de>,
     {
         let s: String = Deserialize::deserialize(deserializer)?;
-        if s.starts_with("file://") {
-            Ok(Uri::File(s.trim_start_matches("file://").into()))
-        } else {
-            Ok(Uri::Url(s))
+        Ok(s.into())
+    }
+}
+
+impl Uri {
+    /// Scheme of the URI without the trailing `:`, e.g. `file` or `https`.
+    pub fn scheme(&self) -> Option<&str> {
+        match self {
+            Uri::File(_) => Some("file"),
+            Uri::Url(url) => url.split_once(':').map(|(scheme, _)| scheme),
+        }
+    }
+}
+
+impl From<String> for Uri {
+    fn from(s: String) -> Self {
+        match s.strip_prefix("file://") {
+            Some(path) => Uri::File(path.into()),
+            None => Uri::Url(s),
         }
     }
 }
@@ -189,7 +206,7 @@ impl<'a> Message for InitializeResourceReaderResponse<'a> {
 
 // Supporting Types
 
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ClientResourceReader {
     pub scheme: String,
@@ -197,7 +214,7 @@ pub struct ClientResourceReader {
     pub is_globbable: bool,
 }
 
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ClientModuleReader {
     pub scheme: String,
@@ -206,7 +223,7 @@ pub struct ClientModuleReader {
     pub is_local: bool,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum ProjectType {
     #[default]
@@ -214,7 +231,7 @@ pub enum ProjectType {
 }
 
 #[skip_serializing_none]
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Project {
     #[serde(rename = "type")]
@@ -224,6 +241,7 @@ pub struct Project {
     pub dependencies: HashMap<String, ProjectDependency>,
 }
 
+#[cfg(feature = "json")]
 #[derive(Default, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Dependencies {
@@ -231,33 +249,33 @@ struct Dependencies {
 }
 
 impl Project {
+    /// Reads a project whose dependencies were resolved with `pkl project resolve`.
+    #[cfg(feature = "json")]
     pub fn from_path(root_dir: impl AsRef<Path>) -> Result<Self, ProjectError> {
         let project_file = root_dir.as_ref().join("PklProject");
         let contents = fs::read(root_dir.as_ref().join("PklProject.deps.json"))?;
         let deps: Dependencies = serde_json::from_slice(&contents)?;
-        let mut project = Project::default();
-
-        project.ty = ProjectType::Local;
-        project.project_file_uri = Uri::File(project_file);
-        project.dependencies = deps
+        let dependencies = deps
             .resolved_dependencies
             .into_iter()
             .filter_map(|(uri, dep)| {
-                if let Some((path, _)) = uri.rsplit_once('@') {
-                    if let Some((_, name)) = path.rsplit_once('/') {
-                        return Some((name.to_string(), dep));
-                    }
-                }
+                let (path, _) = uri.rsplit_once('@')?;
+                let (_, name) = path.rsplit_once('/')?;
 
-                None
+                Some((name.to_string(), dep))
             })
             .collect();
 
-        Ok(project)
+        Ok(Project {
+            ty: ProjectType::Local,
+            package_uri: None,
+            project_file_uri: Uri::File(project_file),
+            dependencies,
+        })
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "type")]
 pub enum ProjectDependency {
@@ -266,7 +284,7 @@ pub enum ProjectDependency {
 }
 
 #[skip_serializing_none]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RemoteDependency {
     #[serde(alias = "uri")]
@@ -274,22 +292,42 @@ pub struct RemoteDependency {
     pub checksums: Option<Checksums>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Checksums {
     pub sha256: String,
 }
 
+/// HTTP settings for package and resource fetches.
+///
+/// The message passing API (as of pkl 0.28) has no connect or read timeout for
+/// individual fetches; the only bound is the evaluator-wide `timeout_seconds`.
 #[skip_serializing_none]
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Http {
+    /// PEM-encoded certificates pkl trusts for HTTPS, replacing its default ones.
     pub ca_certificates: Option<Vec<u8>>,
     pub proxy: Option<Proxy>,
 }
 
+impl Http {
+    /// Adds the PEM certificates in the file at `path` to `ca_certificates`.
+    pub fn with_ca_file(mut self, path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let pem = fs::read(path)?;
+        let certificates = self.ca_certificates.get_or_insert_with(Vec::new);
+
+        if !certificates.is_empty() && !certificates.ends_with(b"\n") {
+            certificates.push(b'\n');
+        }
+        certificates.extend(pem);
+
+        Ok(self)
+    }
+}
+
 #[skip_serializing_none]
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Proxy {
     pub address: Option<String>,
@@ -0,0 +1,656 @@
+use std::{collections::HashSet, fmt, vec};
+
+use serde::{
+    Deserialize, Deserializer,
+    de::{
+        self, IntoDeserializer, Visitor,
+        value::{BorrowedStrDeserializer, MapDeserializer, SeqDeserializer},
+    },
+    forward_to_deserialize_any,
+};
+
+use crate::{
+    errors::ValueError,
+    server::{Value, key_segment, name_segment},
+};
+
+impl de::Error for ValueError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ValueError::Deserialize(msg.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        ValueError::MissingField(field)
+    }
+}
+
+impl Value {
+    /// Deserializes into `T` like `T::deserialize(&value)`, but reports every value
+    /// that fails to deserialize rather than only the first, each as a
+    /// [`ValueError::AtPath`] with a path joined like [`Value::flatten`]'s.
+    ///
+    /// After an error, deserialization is retried with the failing value left out,
+    /// until it succeeds or fails at the root. Errors that only arise because a value
+    /// was left out, such as the missing field it leaves behind, aren't reported.
+    /// Values below an enum or a `#[serde(flatten)]` field are reported at the path of
+    /// the enum or flattened field.
+    pub fn deserialize_collecting<'de, T: Deserialize<'de>>(
+        &'de self,
+    ) -> Result<T, Vec<ValueError>> {
+        let mut skipped = HashSet::new();
+        let mut errors = Vec::new();
+
+        loop {
+            let root = Tracked {
+                value: self,
+                path: String::new(),
+                skipped: &skipped,
+            };
+            let (path, source) = match T::deserialize(root) {
+                Ok(value) if errors.is_empty() => return Ok(value),
+                Ok(_) => return Err(errors),
+                Err(ValueError::AtPath { path, source }) => (path, *source),
+                Err(err) => (String::new(), err),
+            };
+
+            match &source {
+                ValueError::MissingField(field) => {
+                    let field_path = join(&path, name_segment(field));
+
+                    if !skipped.contains(&field_path) {
+                        errors.push(at_path(field_path, source));
+                    }
+                }
+                _ if skipped.iter().any(|skipped| is_below(skipped, &path)) => {}
+                _ => errors.push(at_path(path.clone(), source)),
+            }
+
+            if path.is_empty() || !skipped.insert(path) {
+                return Err(errors);
+            }
+        }
+    }
+}
+
+fn join(path: &str, segment: impl fmt::Display) -> String {
+    match path {
+        "" => segment.to_string(),
+        path => format!("{path}.{segment}"),
+    }
+}
+
+/// Whether `path` is a descendant of `ancestor`.
+fn is_below(path: &str, ancestor: &str) -> bool {
+    ancestor.is_empty()
+        || path
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Attaches `path` to `err`, unless it already carries the path of a value further
+/// down.
+fn at_path(path: String, err: ValueError) -> ValueError {
+    match err {
+        err @ ValueError::AtPath { .. } => err,
+        err if path.is_empty() => err,
+        err => ValueError::AtPath {
+            path,
+            source: Box::new(err),
+        },
+    }
+}
+
+impl<'de> IntoDeserializer<'de, ValueError> for &'de Value {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+/// Visits the entries of `$entries`, an iterator of key/value pairs, as a map and
+/// checks that all of them were consumed.
+macro_rules! visit_map {
+    ($entries:expr, $visitor:expr) => {{
+        let mut map = MapDeserializer::<_, ValueError>::new($entries);
+        let value = $visitor.visit_map(&mut map)?;
+        map.end()?;
+
+        Ok(value)
+    }};
+}
+
+/// Deserializes the same shapes [`Value`] serializes to: object properties and
+/// string-keyed maps are maps, arrays, listings, sets and pairs are sequences,
+/// durations and data sizes are strings such as `5.min`, and int sequences are maps
+/// with `start`, `end` and `step`. An object without properties deserializes as its
+/// entries when it has any, or as a sequence of its elements otherwise.
+///
+/// Integers deserialize into any numeric type they fit in, and floats into integer
+/// types when they have no fractional part. `Null` is `None` or `()`.
+impl<'de> Deserializer<'de> for &'de Value {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Value::Null | Value::Function => visitor.visit_unit(),
+            Value::Int(n) => visitor.visit_i64(*n),
+            Value::Uint(n) => visitor.visit_u64(*n),
+            Value::Float(n) => visitor.visit_f64(*n),
+            Value::Bool(b) => visitor.visit_bool(*b),
+            Value::String(s) | Value::Regex(s) => visitor.visit_borrowed_str(s),
+            Value::Class { name, .. } | Value::TypeAlias { name, .. } => {
+                visitor.visit_borrowed_str(name)
+            }
+            Value::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            Value::Duration { value, unit } => visitor.visit_string(format!("{value}.{unit}")),
+            Value::DataSize { value, unit } => visitor.visit_string(format!("{value}.{unit}")),
+            Value::IntSeq { start, end, step } => {
+                let fields = [("start", *start), ("end", *end), ("step", *step)];
+                visit_map!(fields.into_iter(), visitor)
+            }
+            Value::Object(object) if !object.properties.is_empty() => visit_map!(
+                object
+                    .properties
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value)),
+                visitor
+            ),
+            Value::Object(object) if !object.entries.is_empty() => {
+                visit_map!(
+                    object.entries.iter().map(|(key, value)| (key, value)),
+                    visitor
+                )
+            }
+            Value::Object(object) => visit_seq(object.elements.iter(), visitor),
+            Value::Pair(first, second) => visit_seq([&**first, &**second].into_iter(), visitor),
+            Value::Array(values) | Value::Listing(values) | Value::Set(values) => {
+                visit_seq(values.iter(), visitor)
+            }
+            Value::Map(entries) | Value::Mapping(entries) => {
+                visit_map!(entries.iter().map(|(key, value)| (key, value)), visitor)
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Value::Null => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Unit variants are strings; other variants are maps with a single entry keyed by
+    /// the variant name.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            Value::String(variant) => visitor.visit_enum(variant.as_str().into_deserializer()),
+            Value::Object(object) if object.properties.len() == 1 => {
+                let (variant, value) = object.properties.iter().next().expect("one property");
+                visitor.visit_enum(EnumDeserializer { variant, value })
+            }
+            Value::Map(entries) | Value::Mapping(entries) if entries.len() == 1 => {
+                let (Value::String(variant), value) = &entries[0] else {
+                    return Err(ValueError::UnexpectedValue);
+                };
+                visitor.visit_enum(EnumDeserializer { variant, value })
+            }
+            _ => Err(ValueError::UnexpectedValue),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        deserialize_integer(self, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Visits a float without a fractional part as an integer, so it deserializes into
+/// integer types.
+fn deserialize_integer<'de, V: Visitor<'de>>(
+    value: &'de Value,
+    visitor: V,
+) -> Result<V::Value, ValueError> {
+    match value {
+        Value::Float(n) if n.fract() == 0.0 && *n >= 0.0 && *n <= u64::MAX as f64 => {
+            visitor.visit_u64(*n as u64)
+        }
+        Value::Float(n) if n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < 0.0 => {
+            visitor.visit_i64(*n as i64)
+        }
+        value => value.deserialize_any(visitor),
+    }
+}
+
+fn visit_seq<'de, V: Visitor<'de>>(
+    values: impl Iterator<Item = &'de Value>,
+    visitor: V,
+) -> Result<V::Value, ValueError> {
+    let mut seq = SeqDeserializer::new(values);
+    let value = visitor.visit_seq(&mut seq)?;
+    seq.end()?;
+
+    Ok(value)
+}
+
+struct EnumDeserializer<'de> {
+    variant: &'de str,
+    value: &'de Value,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer<'de> {
+    type Error = ValueError;
+    type Variant = &'de Value;
+
+    fn variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Self::Variant), Self::Error> {
+        let variant = seed.deserialize(IntoDeserializer::<ValueError>::into_deserializer(
+            self.variant,
+        ))?;
+        Ok((variant, self.value))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &'de Value {
+    type Error = ValueError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<S::Value, Self::Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_any(visitor)
+    }
+}
+
+/// Deserializer for [`Value::deserialize_collecting`]: deserializes like `&Value`,
+/// while tracking the path of every value and leaving out the `skipped` ones.
+struct Tracked<'de, 's> {
+    value: &'de Value,
+    path: String,
+    skipped: &'s HashSet<String>,
+}
+
+impl<'de, 's> Tracked<'de, 's> {
+    fn visit_map<V: Visitor<'de>>(
+        self,
+        entries: Vec<(Key<'de>, &'de Value)>,
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        visitor.visit_map(TrackedMap {
+            entries: entries.into_iter(),
+            value: None,
+            path: self.path,
+            skipped: self.skipped,
+        })
+    }
+
+    fn visit_seq<V: Visitor<'de>>(
+        self,
+        elements: impl IntoIterator<Item = (String, &'de Value)>,
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        let elements: Vec<_> = elements
+            .into_iter()
+            .map(|(segment, value)| (join(&self.path, segment), value))
+            .filter(|(path, _)| !self.skipped.contains(path))
+            .collect();
+
+        visitor.visit_seq(TrackedSeq {
+            elements: elements.into_iter(),
+            skipped: self.skipped,
+        })
+    }
+}
+
+/// Forwards to the deserializer of the value itself, for shapes that don't contain
+/// other values.
+macro_rules! forward_to_value {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.value.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Tracked<'de, '_> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Value::Object(object) if !object.properties.is_empty() => {
+                let properties = object.properties.iter();
+                let entries = properties
+                    .map(|(name, value)| (Key::Name(name), value))
+                    .collect();
+                self.visit_map(entries, visitor)
+            }
+            Value::Object(object) if !object.entries.is_empty() => {
+                let entries = object.entries.iter();
+                let entries = entries.map(|(key, value)| (Key::Value(key), value));
+                self.visit_map(entries.collect(), visitor)
+            }
+            Value::Object(object) => {
+                let elements = object.elements.iter().enumerate();
+                self.visit_seq(elements.map(|(i, value)| (i.to_string(), value)), visitor)
+            }
+            Value::Pair(first, second) => self.visit_seq(
+                [
+                    ("first".to_string(), &**first),
+                    ("second".to_string(), &**second),
+                ],
+                visitor,
+            ),
+            Value::Array(values) | Value::Listing(values) | Value::Set(values) => {
+                let elements = values.iter().enumerate();
+                self.visit_seq(elements.map(|(i, value)| (i.to_string(), value)), visitor)
+            }
+            Value::Map(entries) | Value::Mapping(entries) => {
+                let entries = entries.iter();
+                let entries = entries.map(|(key, value)| (Key::Value(key), value));
+                self.visit_map(entries.collect(), visitor)
+            }
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_value! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64 deserialize_i128
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_u128
+    }
+
+    forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Key of a map entry or object property.
+enum Key<'de> {
+    Name(&'de str),
+    Value(&'de Value),
+}
+
+impl Key<'_> {
+    fn segment(&self) -> String {
+        match self {
+            Key::Name(name) => name_segment(name),
+            Key::Value(key) => key_segment(key),
+        }
+    }
+}
+
+struct TrackedMap<'de, 's> {
+    entries: vec::IntoIter<(Key<'de>, &'de Value)>,
+    /// Value and path of the entry whose key was visited last.
+    value: Option<(String, &'de Value)>,
+    path: String,
+    skipped: &'s HashSet<String>,
+}
+
+impl<'de> de::MapAccess<'de> for TrackedMap<'de, '_> {
+    type Error = ValueError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        for (key, value) in self.entries.by_ref() {
+            let path = join(&self.path, key.segment());
+
+            if self.skipped.contains(&path) {
+                continue;
+            }
+
+            let key = match key {
+                Key::Name(name) => seed.deserialize(BorrowedStrDeserializer::new(name)),
+                Key::Value(key) => seed.deserialize(key),
+            }
+            .map_err(|err| at_path(path.clone(), err))?;
+            self.value = Some((path, value));
+
+            return Ok(Some(key));
+        }
+
+        Ok(None)
+    }
+
+    fn next_value_seed<S: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<S::Value, Self::Error> {
+        let (path, value) = self
+            .value
+            .take()
+            .ok_or_else(|| <ValueError as de::Error>::custom("value requested before its key"))?;
+        let tracked = Tracked {
+            value,
+            path: path.clone(),
+            skipped: self.skipped,
+        };
+
+        seed.deserialize(tracked).map_err(|err| at_path(path, err))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct TrackedSeq<'de, 's> {
+    /// Remaining elements with their paths.
+    elements: vec::IntoIter<(String, &'de Value)>,
+    skipped: &'s HashSet<String>,
+}
+
+impl<'de> de::SeqAccess<'de> for TrackedSeq<'de, '_> {
+    type Error = ValueError;
+
+    fn next_element_seed<S: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Self::Error> {
+        let Some((path, value)) = self.elements.next() else {
+            return Ok(None);
+        };
+        let tracked = Tracked {
+            value,
+            path: path.clone(),
+            skipped: self.skipped,
+        };
+
+        seed.deserialize(tracked)
+            .map(Some)
+            .map_err(|err| at_path(path, err))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.elements.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use crate::{errors::ValueError, value};
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Server {
+        host: String,
+        port: u16,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Config {
+        name: String,
+        server: Server,
+        replicas: u32,
+        tags: Vec<String>,
+    }
+
+    fn paths(errors: &[ValueError]) -> Vec<&str> {
+        errors
+            .iter()
+            .map(|err| match err {
+                ValueError::AtPath { path, .. } => path.as_str(),
+                _ => "",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn collects_every_failing_field() {
+        let value = value!({
+            "name" => 5,
+            "server" => { "port" => "http" },
+            "replicas" => (-1),
+            "tags" => ["a", 2, "c", false],
+        });
+
+        let errors = value.deserialize_collecting::<Config>().unwrap_err();
+
+        assert_eq!(
+            paths(&errors),
+            [
+                "name",
+                "server.port",
+                "server.host",
+                "replicas",
+                "tags.1",
+                "tags.3"
+            ]
+        );
+        assert!(matches!(
+            &errors[2],
+            ValueError::AtPath { source, .. } if matches!(**source, ValueError::MissingField("host"))
+        ));
+    }
+
+    #[test]
+    fn valid_value_deserializes() {
+        let value = value!({
+            "name" => "web",
+            "server" => { "host" => "localhost", "port" => 8080 },
+            "replicas" => 2,
+            "tags" => ["a"],
+        });
+
+        let config = value.deserialize_collecting::<Config>().unwrap();
+
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.tags, ["a"]);
+    }
+
+    #[test]
+    fn error_at_the_root_is_reported_once() {
+        let errors = value!("web")
+            .deserialize_collecting::<Config>()
+            .unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert!(!matches!(errors[0], ValueError::AtPath { .. }));
+    }
+}
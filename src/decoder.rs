@@ -1,16 +1,17 @@
-use std::{collections::HashMap, io::Read};
+use std::io::{self, Cursor, Read};
 
+use indexmap::IndexMap;
 use rmp::{
     Marker,
     decode::{MarkerReadError, RmpRead},
 };
-use serde::de::DeserializeOwned;
+use serde::de::{DeserializeOwned, IgnoredAny};
 use tracing::instrument;
 
 use crate::{
     errors::{Error, ValueError},
     protocol::Message,
-    server::{Object, Response, Value},
+    server::{EvaluateResponse, Object, Response, Value},
 };
 
 macro_rules! decode {
@@ -22,31 +23,298 @@ macro_rules! decode {
     };
 }
 
-pub struct Decoder<R: Read + RmpRead> {
-    reader: R,
+/// One member of an object on the wire.
+enum Member {
+    Property(String, Value),
+    Entry(Value, Value),
+    Element(Value),
 }
 
-impl<R: Read + RmpRead> Decoder<R>
-where
-    R: RmpRead<Error = std::io::Error>,
-{
+/// Anomalies noticed while decoding that don't prevent producing a value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeWarning {
+    /// A property of a typed (non-`Dynamic`) object decoded as `null`. Nullable
+    /// properties are reported too since the class declaration isn't on the wire,
+    /// so treat this as a hint when tracking down schema drift.
+    NullProperty {
+        class_name: String,
+        property: String,
+    },
+    /// An object member with a code this crate doesn't model was skipped.
+    UnknownMember { class_name: String, code: u8 },
+}
+
+struct CountingReader<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// Decodes a value in pkl's binary encoding, such as the `result` of an
+/// [`EvaluateResponse`] that was stored earlier.
+pub fn decode_bytes(bytes: &[u8]) -> Result<Value, ValueError> {
+    Decoder::new(Cursor::new(bytes)).decode()
+}
+
+/// Most elements or entries room is made for before any of them are read. Lengths
+/// come from the input, so larger collections grow as they are decoded instead of
+/// letting a malformed length allocate gigabytes up front.
+const MAX_PREALLOCATED: usize = 1024;
+
+/// Appends exactly `len` bytes from `reader` to `buf`, growing `buf` as the bytes
+/// arrive rather than allocating `len` bytes up front.
+fn read_exactly(reader: impl Read, len: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+    let start = buf.len();
+    reader.take(len as u64).read_to_end(buf)?;
+
+    if buf.len() - start < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(())
+}
+
+/// Finds where messages end in a buffer that fills up in chunks, without decoding
+/// them. Scanning resumes where the previous call stopped, so each byte is looked
+/// at once no matter how many reads a message takes to arrive.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub(crate) struct MessageScanner {
+    /// Start of the next value to look at.
+    pos: usize,
+    /// Values still to be found before the message is complete.
+    pending: u64,
+}
+
+#[cfg(feature = "tokio")]
+impl Default for MessageScanner {
+    fn default() -> Self {
+        Self { pos: 0, pending: 1 }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl MessageScanner {
+    /// Length of the first complete MessagePack value in `buf`, or `None` when `buf`
+    /// ends before it does. `buf` has to start with the bytes given to the previous
+    /// calls; once a length is returned the scanner starts over for the next message.
+    pub(crate) fn message_len(&mut self, buf: &[u8]) -> Result<Option<usize>, ValueError> {
+        while self.pending > 0 {
+            let Some(&byte) = buf.get(self.pos) else {
+                return Ok(None);
+            };
+            let mut pos = self.pos + 1;
+
+            let (skip, values) = match Marker::from_u8(byte) {
+                Marker::FixPos(_)
+                | Marker::FixNeg(_)
+                | Marker::Null
+                | Marker::True
+                | Marker::False => (0, 0),
+                Marker::U8 | Marker::I8 => (1, 0),
+                Marker::U16 | Marker::I16 => (2, 0),
+                Marker::U32 | Marker::I32 | Marker::F32 => (4, 0),
+                Marker::U64 | Marker::I64 | Marker::F64 => (8, 0),
+                Marker::FixStr(n) => (n as usize, 0),
+                Marker::Str8 | Marker::Bin8 => match read_len(buf, &mut pos, 1) {
+                    Some(n) => (n, 0),
+                    None => return Ok(None),
+                },
+                Marker::Str16 | Marker::Bin16 => match read_len(buf, &mut pos, 2) {
+                    Some(n) => (n, 0),
+                    None => return Ok(None),
+                },
+                Marker::Str32 | Marker::Bin32 => match read_len(buf, &mut pos, 4) {
+                    Some(n) => (n, 0),
+                    None => return Ok(None),
+                },
+                Marker::FixArray(n) => (0, u64::from(n)),
+                Marker::Array16 => match read_len(buf, &mut pos, 2) {
+                    Some(n) => (0, n as u64),
+                    None => return Ok(None),
+                },
+                Marker::Array32 => match read_len(buf, &mut pos, 4) {
+                    Some(n) => (0, n as u64),
+                    None => return Ok(None),
+                },
+                Marker::FixMap(n) => (0, 2 * u64::from(n)),
+                Marker::Map16 => match read_len(buf, &mut pos, 2) {
+                    Some(n) => (0, 2 * n as u64),
+                    None => return Ok(None),
+                },
+                Marker::Map32 => match read_len(buf, &mut pos, 4) {
+                    Some(n) => (0, 2 * n as u64),
+                    None => return Ok(None),
+                },
+                Marker::FixExt1 => (2, 0),
+                Marker::FixExt2 => (3, 0),
+                Marker::FixExt4 => (5, 0),
+                Marker::FixExt8 => (9, 0),
+                Marker::FixExt16 => (17, 0),
+                Marker::Ext8 => match read_len(buf, &mut pos, 1) {
+                    Some(n) => (n + 1, 0),
+                    None => return Ok(None),
+                },
+                Marker::Ext16 => match read_len(buf, &mut pos, 2) {
+                    Some(n) => (n + 1, 0),
+                    None => return Ok(None),
+                },
+                Marker::Ext32 => match read_len(buf, &mut pos, 4) {
+                    Some(n) => (n + 1, 0),
+                    None => return Ok(None),
+                },
+                marker @ Marker::Reserved => return Err(ValueError::InvalidMarker(marker)),
+            };
+
+            self.pos = pos + skip;
+            self.pending = self.pending - 1 + values;
+        }
+
+        if self.pos > buf.len() {
+            return Ok(None);
+        }
+
+        Ok(Some(std::mem::take(self).pos))
+    }
+}
+
+/// Reads the big-endian length of `width` bytes at `pos` and moves past it.
+#[cfg(feature = "tokio")]
+fn read_len(buf: &[u8], pos: &mut usize, width: usize) -> Option<usize> {
+    let bytes = buf.get(*pos..*pos + width)?;
+    *pos += width;
+
+    Some(
+        bytes
+            .iter()
+            .fold(0, |len, byte| (len << 8) | usize::from(*byte)),
+    )
+}
+
+/// Deepest nesting [`Decoder`] accepts unless told otherwise. Decoding recurses once
+/// per level, and this keeps an optimized build within a 2 MiB thread stack. Debug
+/// builds use about twenty times more stack per level, so lower the limit with
+/// [`Decoder::with_max_depth`] when decoding on small threads there.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// Shape of the values decoded so far, see [`Decoder::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// Number of values produced, counting every element, entry key and value, and
+    /// property value of nested collections and objects.
+    pub nodes: u64,
+    /// Deepest nesting reached, where a scalar at the root has depth 1.
+    pub max_depth: usize,
+}
+
+pub struct Decoder<R: Read> {
+    reader: CountingReader<R>,
+    warnings: Option<Vec<DecodeWarning>>,
+    stats: DecodeStats,
+    depth: usize,
+    max_nodes: Option<u64>,
+    max_depth: usize,
+}
+
+impl<R: Read> Decoder<R> {
     pub fn new(reader: R) -> Self {
-        Self { reader }
+        Self {
+            reader: CountingReader {
+                inner: reader,
+                position: 0,
+            },
+            warnings: None,
+            stats: DecodeStats::default(),
+            depth: 0,
+            max_nodes: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    /// Fails decoding with [`ValueError::TooManyNodes`] once more than `limit`
+    /// values have been produced.
+    pub fn with_max_nodes(mut self, limit: Option<u64>) -> Self {
+        self.max_nodes = limit;
+        self
+    }
+
+    /// Fails decoding with [`ValueError::TooDeep`] once values nest more than `limit`
+    /// levels deep. Defaults to [`DEFAULT_MAX_DEPTH`].
+    pub fn with_max_depth(mut self, limit: usize) -> Self {
+        self.max_depth = limit;
+        self
+    }
+
+    pub fn stats(&self) -> DecodeStats {
+        self.stats
+    }
+
+    /// Number of bytes consumed from the underlying reader so far.
+    pub fn position(&self) -> u64 {
+        self.reader.position
+    }
+
+    pub fn with_warnings(mut self) -> Self {
+        self.warnings = Some(Vec::new());
+        self
     }
 
-    fn marker(&mut self) -> Result<Marker, MarkerReadError<std::io::Error>> {
+    pub fn warnings(&self) -> &[DecodeWarning] {
+        self.warnings.as_deref().unwrap_or_default()
+    }
+
+    fn check_null_properties(&mut self, class_name: &str, properties: &IndexMap<String, Value>) {
+        let Some(warnings) = self.warnings.as_mut() else {
+            return;
+        };
+
+        if class_name == "Dynamic" {
+            return;
+        }
+
+        for (name, value) in properties {
+            if matches!(value, Value::Null) {
+                warnings.push(DecodeWarning::NullProperty {
+                    class_name: class_name.to_string(),
+                    property: name.clone(),
+                });
+            }
+        }
+    }
+
+    fn marker(&mut self) -> Result<Marker, MarkerReadError<io::Error>> {
         rmp::decode::read_marker(&mut self.reader)
     }
 
     fn decode_string(&mut self, len: usize) -> Result<String, ValueError> {
-        let mut buff = vec![0u8; len as usize];
-        self.reader.read_exact(&mut buff)?;
+        let offset = self.position();
+        let buff = self.read_bin(len)?;
+
+        String::from_utf8(buff).map_err(|source| ValueError::Utf8 {
+            offset,
+            len,
+            source,
+        })
+    }
+
+    fn read_bin(&mut self, len: usize) -> Result<Vec<u8>, ValueError> {
+        let mut bytes = Vec::new();
+        read_exactly(&mut self.reader, len, &mut bytes)?;
 
-        Ok(String::from_utf8(buff)?)
+        Ok(bytes)
     }
 
+    /// Decodes one object member. Members with an unknown code are decoded and
+    /// discarded, returning `None`.
     #[instrument(skip(self))]
-    fn decode_property(&mut self) -> Result<(String, Value), ValueError> {
+    fn decode_member(&mut self, class_name: &str) -> Result<Option<Member>, ValueError> {
         let marker = self.marker()?;
 
         if !matches!(marker, Marker::FixArray(3)) {
@@ -57,18 +325,58 @@ where
 
         match code {
             0x10 => {
-                let name: String = self.decode()?.try_into()?;
+                let name = self.decode()?.try_into()?;
                 let value = self.decode()?;
 
-                Ok((name, value))
+                Ok(Some(Member::Property(name, value)))
             }
-            _ => unimplemented!(),
+            0x11 => {
+                let key = self.decode()?;
+                let value = self.decode()?;
+
+                Ok(Some(Member::Entry(key, value)))
+            }
+            0x12 => {
+                // The index is implied by the order elements are sent in.
+                self.decode()?;
+                let value = self.decode()?;
+
+                Ok(Some(Member::Element(value)))
+            }
+            code => {
+                self.decode()?;
+                self.decode()?;
+
+                if let Some(warnings) = self.warnings.as_mut() {
+                    warnings.push(DecodeWarning::UnknownMember {
+                        class_name: class_name.to_string(),
+                        code,
+                    });
+                }
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads `n` entries, each a key followed by its value.
+    #[instrument(skip(self))]
+    fn decode_map(&mut self, n: usize) -> Result<Value, ValueError> {
+        let mut map = Vec::with_capacity(n.min(MAX_PREALLOCATED));
+
+        for _ in 0..n {
+            let key = self.decode()?;
+            let value = self.decode()?;
+
+            map.push((key, value));
         }
+
+        Ok(Value::Map(map))
     }
 
     #[instrument(skip(self))]
     fn decode_array(&mut self, n: usize) -> Result<Value, ValueError> {
-        let mut array = Vec::with_capacity(n);
+        let mut array = Vec::with_capacity(n.min(MAX_PREALLOCATED));
 
         for _ in 0..n {
             array.push(self.decode()?);
@@ -77,16 +385,42 @@ where
         Ok(Value::Array(array))
     }
 
-    #[instrument(skip(self))]
-    fn decode_properties(&mut self, n: usize) -> Result<HashMap<String, Value>, ValueError> {
-        let mut properties = HashMap::default();
-
+    /// Reads `n` members into `object`.
+    #[instrument(skip(self, object))]
+    fn decode_members(&mut self, object: &mut Object, n: usize) -> Result<(), ValueError> {
         for _ in 0..n {
-            let (key, value) = self.decode_property()?;
-            properties.insert(key, value);
+            match self.decode_member(&object.class_name)? {
+                Some(Member::Property(name, value)) => {
+                    object.properties.insert(name, value);
+                }
+                Some(Member::Entry(key, value)) => object.entries.push((key, value)),
+                Some(Member::Element(value)) => object.elements.push(value),
+                None => {}
+            }
         }
 
-        Ok(properties)
+        Ok(())
+    }
+
+    /// Reads the magnitude and unit of a `Duration` or `DataSize`.
+    fn decode_quantity(&mut self) -> Result<(f64, String), ValueError> {
+        let value = match self.decode_inner(false)? {
+            Value::Float(value) => value,
+            Value::Int(value) => value as f64,
+            Value::Uint(value) => value as f64,
+            _ => return Err(ValueError::UnexpectedValue),
+        };
+        let unit = self.decode_inner(false)?.try_into()?;
+
+        Ok((value, unit))
+    }
+
+    /// Reads the name and module URI of a `Class` or `TypeAlias`.
+    fn decode_type_ref(&mut self) -> Result<(String, String), ValueError> {
+        let name = self.decode_inner(false)?.try_into()?;
+        let module_uri = self.decode_inner(false)?.try_into()?;
+
+        Ok((name, module_uri))
     }
 
     #[instrument(skip(self))]
@@ -97,51 +431,126 @@ where
             Marker::FixArray(_) if custom_type => match self.reader.read_data_u8()? {
                 // Typed, Dynamic
                 0x1 => {
-                    let class_name: String = self.decode_inner(false)?.try_into()?;
-                    let module_uri: String = self.decode_inner(false)?.try_into()?;
-                    let properties = match self.marker()? {
-                        Marker::FixArray(n) => self.decode_properties(n as usize),
-                        Marker::Array16 => {
-                            let n = self.reader.read_data_u16()?;
-                            self.decode_properties(n as usize)
-                        }
-                        Marker::Array32 => {
-                            let n = self.reader.read_data_u32()?;
-                            self.decode_properties(n as usize)
-                        }
-                        marker => Err(ValueError::InvalidMarker(marker)),
-                    }?;
-
-                    Ok(Value::Object(Object {
-                        class_name,
-                        module_uri,
-                        properties,
-                    }))
+                    let mut object = Object {
+                        class_name: self.decode_inner(false)?.try_into()?,
+                        module_uri: self.decode_inner(false)?.try_into()?,
+                        properties: IndexMap::new(),
+                        entries: Vec::new(),
+                        elements: Vec::new(),
+                    };
+                    let n = match self.marker()? {
+                        Marker::FixArray(n) => n as usize,
+                        Marker::Array16 => self.reader.read_data_u16()? as usize,
+                        Marker::Array32 => self.reader.read_data_u32()? as usize,
+                        marker => return Err(ValueError::InvalidMarker(marker)),
+                    };
+
+                    self.decode_members(&mut object, n)?;
+                    self.check_null_properties(&object.class_name, &object.properties);
+
+                    Ok(Value::Object(object))
                 }
+                // Map
+                0x2 => self.decode_inner(false),
                 // Mapping
-                0x3 => self.decode_inner(false),
+                0x3 => match self.decode_inner(false)? {
+                    Value::Map(entries) => Ok(Value::Mapping(entries)),
+                    _ => Err(ValueError::UnexpectedValue),
+                },
+                // List
+                0x4 => match self.decode_inner(false)? {
+                    Value::Array(values) => Ok(Value::Array(values)),
+                    _ => Err(ValueError::UnexpectedValue),
+                },
                 // Listing
-                0x5 => self.decode_inner(false),
+                0x5 => match self.decode_inner(false)? {
+                    Value::Array(values) => Ok(Value::Listing(values)),
+                    _ => Err(ValueError::UnexpectedValue),
+                },
+                // Set
+                0x6 => match self.decode_inner(false)? {
+                    Value::Array(values) => Ok(Value::Set(values)),
+                    _ => Err(ValueError::UnexpectedValue),
+                },
+                // Duration
+                0x7 => {
+                    let (value, unit) = self.decode_quantity()?;
+
+                    Ok(Value::Duration {
+                        value,
+                        unit: unit.parse()?,
+                    })
+                }
+                // DataSize
+                0x8 => {
+                    let (value, unit) = self.decode_quantity()?;
+
+                    Ok(Value::DataSize {
+                        value,
+                        unit: unit.parse()?,
+                    })
+                }
+                // Pair
+                0x9 => {
+                    let first = self.decode()?;
+                    let second = self.decode()?;
+
+                    Ok(Value::Pair(Box::new(first), Box::new(second)))
+                }
+                // IntSeq
+                0xA => {
+                    let mut next = || -> Result<i64, ValueError> {
+                        match self.decode_inner(false)? {
+                            Value::Int(n) => Ok(n),
+                            Value::Uint(n) => n.try_into().map_err(|_| ValueError::UnexpectedValue),
+                            _ => Err(ValueError::UnexpectedValue),
+                        }
+                    };
+
+                    Ok(Value::IntSeq {
+                        start: next()?,
+                        end: next()?,
+                        step: next()?,
+                    })
+                }
+                // Regex
+                0xB => Ok(Value::Regex(self.decode_inner(false)?.try_into()?)),
+                // Class
+                0xC => {
+                    let (name, module_uri) = self.decode_type_ref()?;
+                    Ok(Value::Class { name, module_uri })
+                }
+                // TypeAlias
+                0xD => {
+                    let (name, module_uri) = self.decode_type_ref()?;
+                    Ok(Value::TypeAlias { name, module_uri })
+                }
                 // Function
                 0xE => Ok(Value::Function),
-                c => unimplemented!("code {c} is not implemented"),
+                // Bytes
+                0xF => match self.decode_inner(false)? {
+                    Value::Bytes(bytes) => Ok(Value::Bytes(bytes)),
+                    _ => Err(ValueError::UnexpectedValue),
+                },
+                code => Err(ValueError::UnsupportedCode(code)),
             },
 
-            Marker::I8 => Ok(Value::Int(rmp::decode::read_i8(&mut self.reader)? as i64)),
-            Marker::I16 => Ok(Value::Int(rmp::decode::read_i16(&mut self.reader)? as i64)),
-            Marker::I32 => Ok(Value::Int(rmp::decode::read_i32(&mut self.reader)? as i64)),
-            Marker::I64 => Ok(Value::Int(rmp::decode::read_i64(&mut self.reader)?)),
-            Marker::U8 => Ok(Value::Uint(rmp::decode::read_u8(&mut self.reader)? as u64)),
-            Marker::U16 => Ok(Value::Uint(rmp::decode::read_u16(&mut self.reader)? as u64)),
-            Marker::U32 => Ok(Value::Uint(rmp::decode::read_u32(&mut self.reader)? as u64)),
-            Marker::U64 => Ok(Value::Uint(rmp::decode::read_u64(&mut self.reader)?)),
-            Marker::F32 => Ok(Value::Float(rmp::decode::read_f32(&mut self.reader)? as f64)),
-            Marker::F64 => Ok(Value::Float(rmp::decode::read_f64(&mut self.reader)?)),
+            Marker::I8 => Ok(Value::Int(self.reader.read_data_i8()? as i64)),
+            Marker::I16 => Ok(Value::Int(self.reader.read_data_i16()? as i64)),
+            Marker::I32 => Ok(Value::Int(self.reader.read_data_i32()? as i64)),
+            Marker::I64 => Ok(Value::Int(self.reader.read_data_i64()?)),
+            Marker::U8 => Ok(Value::Uint(self.reader.read_data_u8()? as u64)),
+            Marker::U16 => Ok(Value::Uint(self.reader.read_data_u16()? as u64)),
+            Marker::U32 => Ok(Value::Uint(self.reader.read_data_u32()? as u64)),
+            Marker::U64 => Ok(Value::Uint(self.reader.read_data_u64()?)),
+            Marker::F32 => Ok(Value::Float(self.reader.read_data_f32()? as f64)),
+            Marker::F64 => Ok(Value::Float(self.reader.read_data_f64()?)),
             Marker::Null => Ok(Value::Null),
             Marker::True => Ok(Value::Bool(true)),
             Marker::False => Ok(Value::Bool(false)),
             Marker::FixStr(size) => Ok(Value::String(self.decode_string(size as usize)?)),
             Marker::FixPos(pos) => Ok(Value::Uint(pos as u64)),
+            Marker::FixNeg(neg) => Ok(Value::Int(neg as i64)),
             Marker::Str8 => {
                 let len = self.reader.read_data_u8()?;
                 Ok(Value::String(self.decode_string(len as usize)?))
@@ -154,17 +563,14 @@ where
                 let len = self.reader.read_data_u32()?;
                 Ok(Value::String(self.decode_string(len as usize)?))
             }
-            Marker::FixMap(n) => {
-                let mut map = Vec::with_capacity(n as usize);
-
-                for _ in 0..n {
-                    let value = self.decode()?;
-                    let key = self.decode()?;
-
-                    map.push((key, value));
-                }
-
-                Ok(Value::Map(map))
+            Marker::FixMap(n) => self.decode_map(n as usize),
+            Marker::Map16 => {
+                let n = self.reader.read_data_u16()?;
+                self.decode_map(n as usize)
+            }
+            Marker::Map32 => {
+                let n = self.reader.read_data_u32()?;
+                self.decode_map(n as usize)
             }
             Marker::Array16 => {
                 let n = self.reader.read_data_u16()?;
@@ -175,25 +581,63 @@ where
                 self.decode_array(n as usize)
             }
             Marker::FixArray(n) => self.decode_array(n as usize),
-            marker => unimplemented!("unknown marker: {marker:#?}"),
+            Marker::Bin8 => {
+                let len = self.reader.read_data_u8()?;
+                Ok(Value::Bytes(self.read_bin(len as usize)?))
+            }
+            Marker::Bin16 => {
+                let len = self.reader.read_data_u16()?;
+                Ok(Value::Bytes(self.read_bin(len as usize)?))
+            }
+            Marker::Bin32 => {
+                let len = self.reader.read_data_u32()?;
+                Ok(Value::Bytes(self.read_bin(len as usize)?))
+            }
+            marker => Err(ValueError::UnsupportedMarker(marker)),
         }
     }
 
     #[instrument(skip(self), err(Debug))]
     pub fn decode(&mut self) -> Result<Value, ValueError> {
-        self.decode_inner(true)
+        self.stats.nodes += 1;
+
+        if let Some(limit) = self.max_nodes.filter(|limit| self.stats.nodes > *limit) {
+            return Err(ValueError::TooManyNodes { limit });
+        }
+
+        if self.depth >= self.max_depth {
+            return Err(ValueError::TooDeep {
+                limit: self.max_depth,
+            });
+        }
+
+        self.depth += 1;
+        self.stats.max_depth = self.stats.max_depth.max(self.depth);
+        let value = self.decode_inner(true);
+        self.depth -= 1;
+
+        value
     }
 
     #[instrument(skip(self))]
     pub fn decode_response(&mut self) -> Result<Response, Error> {
+        let code = self.decode_header()?;
+        self.decode_body(code)
+    }
+
+    /// Reads the `[code, body]` framing of a message, returning the code and leaving
+    /// the reader positioned at the body.
+    pub fn decode_header(&mut self) -> Result<u64, Error> {
         let marker = self.marker()?;
 
         if !matches!(marker, Marker::FixArray(len) if len == 2) {
             return Err(Error::InvalidMarker(marker));
         }
 
-        let code: u64 = rmp_serde::from_read(&mut self.reader)?;
+        Ok(rmp_serde::from_read(&mut self.reader)?)
+    }
 
+    pub fn decode_body(&mut self, code: u64) -> Result<Response, Error> {
         Ok(decode!(
             &mut self.reader, code;
             CreateEvaluatorResponse,
@@ -209,6 +653,53 @@ where
         ))
     }
 
+    /// Reads an `EvaluateResponse` body, copying `result` into `buf` rather than a
+    /// freshly allocated vector. The returned response has `result` set to `None`;
+    /// the flag tells whether a result was written to `buf`.
+    pub fn decode_evaluate_response_into(
+        &mut self,
+        buf: &mut Vec<u8>,
+    ) -> Result<(EvaluateResponse, bool), Error> {
+        let len = rmp::decode::read_map_len(&mut self.reader).map_err(ValueError::from)?;
+        let mut response = EvaluateResponse {
+            request_id: 0,
+            evaluator_id: 0,
+            result: None,
+            error: None,
+        };
+        let mut has_result = false;
+
+        buf.clear();
+
+        for _ in 0..len {
+            let key_len = rmp::decode::read_str_len(&mut self.reader).map_err(ValueError::from)?;
+
+            match self.decode_string(key_len as usize)?.as_str() {
+                "requestId" => response.request_id = rmp_serde::from_read(&mut self.reader)?,
+                "evaluatorId" => response.evaluator_id = rmp_serde::from_read(&mut self.reader)?,
+                "error" => response.error = rmp_serde::from_read(&mut self.reader)?,
+                "result" => {
+                    let len = match self.marker()? {
+                        Marker::Null => continue,
+                        Marker::Bin8 => self.reader.read_data_u8().map(usize::from),
+                        Marker::Bin16 => self.reader.read_data_u16().map(usize::from),
+                        Marker::Bin32 => self.reader.read_data_u32().map(|n| n as usize),
+                        marker => return Err(Error::InvalidMarker(marker)),
+                    }
+                    .map_err(ValueError::from)?;
+
+                    read_exactly(&mut self.reader, len, buf)?;
+                    has_result = true;
+                }
+                _ => {
+                    rmp_serde::from_read::<_, IgnoredAny>(&mut self.reader)?;
+                }
+            }
+        }
+
+        Ok((response, has_result))
+    }
+
     pub fn decode_response_typed<T>(&mut self) -> Result<T, Error>
     where
         T: Message + DeserializeOwned,
@@ -217,3 +708,310 @@ where
         self.decode_response()?.try_into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::value;
+
+    fn encode(value: impl Serialize) -> Vec<u8> {
+        rmp_serde::to_vec(&value).unwrap()
+    }
+
+    fn decode(value: impl Serialize) -> Result<Value, ValueError> {
+        decode_bytes(&encode(value))
+    }
+
+    #[test]
+    fn list_decodes_as_array() {
+        let value = decode((0x4, ["a", "b"])).unwrap();
+
+        assert_eq!(
+            value,
+            Value::Array(vec![Value::from("a"), Value::from("b")])
+        );
+    }
+
+    #[test]
+    fn listing_and_list_deserialize_into_vec() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Ports {
+            listing: Vec<u16>,
+            list: Vec<u16>,
+        }
+
+        // [0x3, {"listing": [0x5, [80, 443]], "list": [0x4, [8080, 8443]]}]
+        let mut bytes = vec![0x92, 0x03, 0x82];
+        bytes.extend(encode("listing"));
+        bytes.extend(encode((0x5, [80, 443])));
+        bytes.extend(encode("list"));
+        bytes.extend(encode((0x4, [8080, 8443])));
+
+        let value = decode_bytes(&bytes).unwrap();
+
+        assert!(matches!(
+            &value,
+            Value::Mapping(entries) if matches!(entries[0].1, Value::Listing(_))
+        ));
+        assert_eq!(
+            Ports::deserialize(&value).unwrap(),
+            Ports {
+                listing: vec![80, 443],
+                list: vec![8080, 8443],
+            }
+        );
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        // [0x4, [[0x4, [...]]]], nested far past the limit.
+        let mut bytes = Vec::new();
+        for _ in 0..100_000 {
+            bytes.extend([0x92, 0x04, 0x91]);
+        }
+        bytes.push(0xc0);
+
+        // Room for the default limit in unoptimized builds, see DEFAULT_MAX_DEPTH.
+        let err = std::thread::Builder::new()
+            .stack_size(32 << 20)
+            .spawn(move || decode_bytes(&bytes))
+            .unwrap()
+            .join()
+            .unwrap()
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            ValueError::TooDeep {
+                limit: DEFAULT_MAX_DEPTH
+            }
+        ));
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_decodes() {
+        let mut bytes = Vec::new();
+        for _ in 0..3 {
+            bytes.extend([0x92, 0x04, 0x91]);
+        }
+        bytes.push(0xc0);
+
+        let mut decoder = Decoder::new(bytes.as_slice()).with_max_depth(4);
+
+        assert!(decoder.decode().is_ok());
+        assert_eq!(decoder.stats().max_depth, 4);
+        assert!(matches!(
+            Decoder::new(bytes.as_slice()).with_max_depth(3).decode(),
+            Err(ValueError::TooDeep { limit: 3 })
+        ));
+    }
+
+    #[test]
+    fn unknown_members_are_skipped_with_a_warning() {
+        let object = (
+            0x1,
+            "Config",
+            "file:///config.pkl",
+            (
+                (0x10, "port", 8080),
+                (0x17, "hidden", (0x4, [1, 2])),
+                (0x11, "k", "v"),
+            ),
+        );
+        let mut decoder = Decoder::new(Cursor::new(encode(object))).with_warnings();
+
+        let Value::Object(object) = decoder.decode().unwrap() else {
+            panic!("expected an object");
+        };
+
+        assert_eq!(object.properties["port"], Value::from(8080));
+        assert_eq!(object.entries, [(Value::from("k"), Value::from("v"))]);
+        assert_eq!(
+            decoder.warnings(),
+            [DecodeWarning::UnknownMember {
+                class_name: "Config".to_string(),
+                code: 0x17,
+            }]
+        );
+    }
+
+    #[test]
+    fn class_and_type_alias_decode() {
+        // [0xC, "Server", "file:///app.pkl"]
+        const CLASS: &[u8] = b"\x93\x0c\xa6Server\xaffile:///app.pkl";
+        // [0xD, "Port", "file:///app.pkl"]
+        const TYPE_ALIAS: &[u8] = b"\x93\x0d\xa4Port\xaffile:///app.pkl";
+
+        assert_eq!(
+            decode_bytes(CLASS).unwrap(),
+            Value::Class {
+                name: "Server".to_string(),
+                module_uri: "file:///app.pkl".to_string(),
+            }
+        );
+        assert_eq!(
+            decode_bytes(TYPE_ALIAS).unwrap(),
+            Value::TypeAlias {
+                name: "Port".to_string(),
+                module_uri: "file:///app.pkl".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn negative_fixint_decodes() {
+        // [0x5, [-1, -16, -32]]
+        const LISTING: &[u8] = b"\x92\x05\x93\xff\xf0\xe0";
+
+        assert_eq!(decode_bytes(b"\xff").unwrap(), Value::Int(-1));
+        assert_eq!(
+            decode_bytes(LISTING).unwrap(),
+            Value::Listing(vec![Value::Int(-1), Value::Int(-16), Value::Int(-32)])
+        );
+    }
+
+    #[test]
+    fn maps_keep_keys_before_values() {
+        let map = value!({ "a" => 1, "b" => "two" });
+        assert_eq!(decode(&map).unwrap(), map);
+
+        // Map16 goes through the same entry decoding as FixMap.
+        let entries = (0..20)
+            .map(|i| (Value::from(format!("key{i}")), Value::from(i)))
+            .collect();
+        let map = Value::Map(entries);
+        assert_eq!(decode(&map).unwrap(), map);
+    }
+
+    #[test]
+    fn object_members_are_assembled_by_kind() {
+        let object = (
+            0x1,
+            "Dynamic",
+            "pkl:base",
+            (
+                (0x10, "name", "web"),
+                (0x11, "env", "prod"),
+                (0x12, 0, "first"),
+                (0x10, "port", 80),
+                (0x11, 8080, "alt"),
+                (0x12, 1, "second"),
+            ),
+        );
+
+        let Value::Object(object) = decode(object).unwrap() else {
+            panic!("expected an object");
+        };
+
+        assert_eq!(
+            object.properties.into_iter().collect::<Vec<_>>(),
+            [
+                ("name".to_string(), Value::from("web")),
+                ("port".to_string(), Value::from(80)),
+            ]
+        );
+        assert_eq!(
+            object.entries,
+            [
+                (Value::from("env"), Value::from("prod")),
+                (Value::from(8080), Value::from("alt")),
+            ]
+        );
+        assert_eq!(
+            object.elements,
+            [Value::from("first"), Value::from("second")]
+        );
+    }
+
+    #[test]
+    fn mapping_keyed_by_pairs_is_looked_up_structurally() {
+        // [0x3, {[0x9, 1, 2]: "a", [0x9, 3, 4]: "b"}]
+        let mut bytes = vec![0x92, 0x03, 0x82];
+        bytes.extend(encode((0x9, 1, 2)));
+        bytes.extend(encode("a"));
+        bytes.extend(encode((0x9, 3, 4)));
+        bytes.extend(encode("b"));
+
+        let mapping = decode_bytes(&bytes).unwrap();
+        let pair = |first: i64, second: i64| {
+            Value::Pair(Box::new(Value::Int(first)), Box::new(Value::Int(second)))
+        };
+
+        assert!(matches!(&mapping, Value::Mapping(entries) if entries.len() == 2));
+        assert_eq!(mapping.map_get(&pair(1, 2)), Some(&Value::from("a")));
+        assert_eq!(mapping.map_get(&pair(3, 4)), Some(&Value::from("b")));
+        assert_eq!(mapping.map_get(&pair(2, 1)), None);
+    }
+
+    #[test]
+    fn huge_lengths_fail_without_allocating() {
+        for bytes in [
+            b"\xdd\xff\xff\xff\xff".as_slice(), // array32
+            b"\xdf\xff\xff\xff\xff",            // map32
+            b"\xdb\xff\xff\xff\xffabc",         // str32
+            b"\xc6\xff\xff\xff\xffabc",         // bin32
+        ] {
+            assert!(decode_bytes(bytes).is_err(), "{bytes:x?}");
+        }
+    }
+
+    #[test]
+    fn truncated_strings_and_bytes_are_an_error() {
+        assert!(matches!(decode_bytes(b"\xa5abc"), Err(ValueError::IO(_))));
+        assert!(matches!(
+            decode_bytes(b"\xc4\x05abc"),
+            Err(ValueError::IO(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_custom_type_code_is_unsupported() {
+        assert!(matches!(
+            decode((0x2f, "x")),
+            Err(ValueError::UnsupportedCode(0x2f))
+        ));
+    }
+
+    #[test]
+    fn reserved_and_extension_markers_are_unsupported() {
+        assert!(matches!(
+            decode_bytes(b"\xc1"),
+            Err(ValueError::UnsupportedMarker(Marker::Reserved))
+        ));
+        assert!(matches!(
+            decode_bytes(b"\xd4\x01\x00"),
+            Err(ValueError::UnsupportedMarker(Marker::FixExt1))
+        ));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn message_ends_are_found_as_bytes_arrive() {
+        let first = encode((0x1, [("a", vec![1u8; 300])], [1.5, -2.0]));
+        let second = encode([0x2]);
+        let stream = [first.as_slice(), &second].concat();
+
+        let mut scanner = MessageScanner::default();
+        for end in 0..first.len() {
+            assert_eq!(scanner.message_len(&stream[..end]).unwrap(), None);
+        }
+        assert_eq!(scanner.message_len(&stream).unwrap(), Some(first.len()));
+
+        let rest = &stream[first.len()..];
+        assert_eq!(scanner.message_len(&rest[..1]).unwrap(), None);
+        assert_eq!(scanner.message_len(rest).unwrap(), Some(second.len()));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn reserved_marker_ends_no_message() {
+        let mut scanner = MessageScanner::default();
+
+        assert!(matches!(
+            scanner.message_len(b"\x92\x01\xc1"),
+            Err(ValueError::InvalidMarker(Marker::Reserved))
+        ));
+    }
+}
@@ -19,7 +19,7 @@ impl PklError {
 
         Self {
             message: parts[1].to_string(),
-            trace: Some(parts[2].trim().to_string()),
+            trace: parts.get(2).map(|trace| trace.trim().to_string()),
         }
     }
 }
@@ -34,6 +34,7 @@ impl Display for PklError {
 pub enum ProjectError {
     #[error("I/O error: {0}")]
     IO(#[from] std::io::Error),
+    #[cfg(feature = "json")]
     #[error("failed to parse JSON: {0}")]
     Serde(#[from] serde_json::Error),
 }
@@ -44,14 +45,37 @@ pub enum ValueError {
     IO(#[from] std::io::Error),
     #[error("unexpected value detected")]
     UnexpectedValue,
+    #[error("IntSeq has a step of zero")]
+    ZeroStep,
+    #[error("failed to deserialize: {0}")]
+    Deserialize(String),
+    #[error("missing field `{0}`")]
+    MissingField(&'static str),
+    #[error("{path}: {source}")]
+    AtPath {
+        path: String,
+        source: Box<ValueError>,
+    },
+    #[error("value has more than {limit} nodes")]
+    TooManyNodes { limit: u64 },
+    #[error("value is nested more than {limit} levels deep")]
+    TooDeep { limit: usize },
     #[error("failed to read value: {0}")]
     Read(#[from] rmp::decode::ValueReadError),
-    #[error("failed to read string: {0}")]
-    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("invalid UTF-8 in {len} byte string at offset {offset}: {source}")]
+    Utf8 {
+        offset: u64,
+        len: usize,
+        source: std::string::FromUtf8Error,
+    },
     #[error("failed to read marker: {0:?}")]
     MarkerRead(rmp::decode::MarkerReadError<std::io::Error>),
     #[error("invalid marker: {0:?}")]
     InvalidMarker(rmp::Marker),
+    #[error("unsupported custom type code {0:#x}")]
+    UnsupportedCode(u8),
+    #[error("unsupported marker: {0:?}")]
+    UnsupportedMarker(rmp::Marker),
 }
 
 impl From<rmp::decode::MarkerReadError<std::io::Error>> for ValueError {
@@ -68,6 +92,8 @@ pub enum Error {
     Value(#[from] ValueError),
     #[error("invalid request ID: expected {expected}, got {actual}")]
     InvalidRequestId { expected: u64, actual: u64 },
+    #[error("received a second response to request {request_id}")]
+    DuplicateResponse { request_id: u64 },
     #[error("failed to encode: {0}")]
     Encode(#[from] rmp_serde::encode::Error),
     #[error("failed to decode: {0}")]
@@ -84,6 +110,62 @@ pub enum Error {
     IO(#[from] std::io::Error),
     #[error("stdin/stdout not present")]
     Pipe,
+    #[error(
+        "pkl was not found (tried `{tried}`); install pkl, or set PKL_EXEC to the path of the pkl binary"
+    )]
+    PklNotFound { tried: String },
+    #[error("failed to start `{program}`: {source}")]
+    Spawn {
+        program: String,
+        source: std::io::Error,
+    },
+    #[error(
+        "invalid output format: {0} (expected one of json, jsonnet, pcf, plist, properties, textproto, xml or yaml)"
+    )]
+    InvalidOutputFormat(String),
+    #[error("invalid allow pattern: {0} (allowed modules/resources are Java regular expressions)")]
+    InvalidAllowPattern(String),
+    #[error(
+        "failed to load project: {0} (check that PklProject exists and `pkl project resolve` was run)"
+    )]
+    ProjectNotFound(String),
+    #[error("module text exceeds the limit of {limit} bytes")]
+    ModuleTextTooLarge { limit: usize },
+    #[error("module text is not valid UTF-8: {0}")]
+    InvalidModuleText(std::string::FromUtf8Error),
+    #[error("result of {size} bytes exceeds the limit of {limit} bytes")]
+    ResultTooLarge { size: usize, limit: usize },
+    #[error("invalid glob pattern: {0}")]
+    InvalidGlob(#[from] glob::PatternError),
+    #[error("no response from pkl within {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("evaluation produced no result")]
+    EmptyResult,
+    #[error("schema mismatch: {0}")]
+    SchemaMismatch(String),
+    #[error("{kind} reader for scheme `{scheme}` is registered but `{scheme}:` is not allowed")]
+    ReaderNotAllowed { kind: &'static str, scheme: String },
+    #[error("`{scheme}:` is allowed for {kind}s but no {kind} reader is registered for it")]
+    ReaderMissing { kind: &'static str, scheme: String },
+}
+
+impl Error {
+    /// Maps the error pkl returns when rejecting a `CreateEvaluatorRequest` onto a
+    /// dedicated variant where the cause is recognizable, falling back to `Error::Pkl`.
+    pub fn from_create_error(raw: String) -> Self {
+        let error = PklError::parse(raw);
+        let message = error.message.to_lowercase();
+
+        if message.contains("output format") {
+            Error::InvalidOutputFormat(error.message)
+        } else if message.contains("patternsyntaxexception") || message.contains("near index") {
+            Error::InvalidAllowPattern(error.message)
+        } else if message.contains("pklproject") {
+            Error::ProjectNotFound(error.message)
+        } else {
+            Error::Pkl(error)
+        }
+    }
 }
 
 impl From<rmp::decode::MarkerReadError<std::io::Error>> for Error {
@@ -1,25 +1,139 @@
-use std::io::Cursor;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::SyncSender,
+    },
+    time::Duration,
+};
 
+#[cfg(feature = "regex")]
+use regex::Regex;
+use serde::de::DeserializeOwned;
 use tracing::instrument;
 
 use crate::{
-    client::{CreateEvaluatorRequest, EvaluateRequest, Project, Uri},
+    client::{
+        ClientModuleReader, ClientResourceReader, CloseEvaluator, CreateEvaluatorRequest,
+        EvaluateRequest, Http, Project, ProjectDependency, Uri,
+    },
     decoder::Decoder,
-    errors::{Error, PklError},
-    protocol::Protocol,
-    server::Value,
+    errors::{Error, PklError, ValueError},
+    observer::{LogEvent, MessageObserver},
+    protocol::{Protocol, ServerRequests},
+    reader::{self, InlineResources, ModuleReader, ResourceReader},
+    reflect::{self, ModuleInfo, SourceLocation},
+    server::{CreateEvaluatorResponse, EvaluateResponse, Value, name_segment},
+    session::Session,
 };
 
+/// Upper bound on module text accepted by [`Evaluator::eval_reader`].
+pub const MAX_MODULE_TEXT_LEN: usize = 64 * 1024 * 1024;
+
+/// Module schemes pkl resolves itself, without a client reader.
+const BUILTIN_MODULE_SCHEMES: &[&str] = &[
+    "pkl",
+    "file",
+    "http",
+    "https",
+    "jar",
+    "modulepath",
+    "package",
+    "projectpackage",
+    "repl",
+];
+
+/// Resource schemes pkl reads itself, without a client reader.
+const BUILTIN_RESOURCE_SCHEMES: &[&str] = &[
+    "env",
+    "prop",
+    "file",
+    "http",
+    "https",
+    "jar",
+    "modulepath",
+    "package",
+    "projectpackage",
+];
+
+/// How much longer than [`EvalOpts::timeout`] the evaluator waits for pkl to
+/// answer, which leaves pkl time to report its own timeout error.
+pub const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+/// Pattern for [`Evaluator::eval_dir`] matching every module in a directory tree.
+pub const DEFAULT_MODULE_GLOB: &str = "**/*.pkl";
+
+/// Path and result of every module evaluated by [`Evaluator::eval_dir`].
+pub type DirResults = Vec<(PathBuf, Result<Option<Value>, Error>)>;
+
 pub struct Evaluator {
-    request_id: u64,
+    request_id: AtomicU64,
     proto: Protocol,
 }
 
+#[derive(Clone)]
 pub struct EvalOpts {
     pub allowed_modules: Vec<String>,
     pub allowed_resources: Vec<String>,
-    pub output_format: String,
+    /// Format used to render `output.text`. `None` leaves it out of the create
+    /// request so pkl uses whatever renderer the module itself declares.
+    pub output_format: Option<OutputFormat>,
     pub project: Option<Project>,
+    /// Entries of pkl's module path, resolving `modulepath:` imports, e.g. directories
+    /// or jars of shared modules. Used alongside `project`. When both are unset, the
+    /// module path is the evaluated module's URI.
+    pub module_paths: Vec<String>,
+    /// Proxy and CA certificates pkl uses for HTTP(S) requests, e.g. to download
+    /// packages.
+    pub http: Option<Http>,
+    /// Results larger than this many bytes are rejected with
+    /// `Error::ResultTooLarge` instead of being decoded. Unlimited by default.
+    pub max_result_bytes: Option<usize>,
+    /// Results with more values than this, counting every nested element, entry and
+    /// property, fail to decode with `ValueError::TooManyNodes`. Unlimited by default.
+    pub max_nodes: Option<u64>,
+    /// Time pkl allows each evaluation before failing it, rounded up to whole
+    /// seconds. The server enforces this, and the evaluator gives up with
+    /// [`Error::Timeout`] when pkl sends nothing for [`TIMEOUT_GRACE`] longer, in case
+    /// the server stops responding. See [`Protocol::set_read_timeout`] for a limit
+    /// that also applies without this option.
+    pub timeout: Option<Duration>,
+    /// Directory pkl caches downloaded packages in, instead of `~/.pkl/cache`.
+    pub cache_dir: Option<PathBuf>,
+    /// Restricts `file:` modules and resources to paths below this directory: pkl
+    /// refuses to read files outside it, even ones matched by the allow lists. Note
+    /// that [`EvalOpts::file_root`] also narrows the allow lists.
+    pub root_dir: Option<PathBuf>,
+    /// Environment variables visible to Pkl through `read("env:<name>")` when `env:`
+    /// resources are allowed. Pkl sees none of the process environment unless
+    /// [`EvalOpts::inherit_env`] copies it in.
+    pub env: HashMap<String, String>,
+    /// External properties, readable from Pkl with `read("prop:<name>")` when
+    /// `prop:` resources are allowed.
+    pub properties: HashMap<String, String>,
+    /// External properties given as values, rendered to the text form Pkl reads back
+    /// most easily, and taking precedence over `properties` of the same name:
+    ///
+    /// - strings are passed verbatim;
+    /// - integers, floats and booleans as Pkl literals, read back with `toInt()`,
+    ///   `toFloat()` and `toBoolean()`, with `NaN`, `Infinity` and `-Infinity` for
+    ///   non-finite floats;
+    /// - durations and data sizes as Pkl literals such as `5.min` and `512.mib`;
+    /// - `Null` leaves the property unset, so `read?` yields `null`.
+    ///
+    /// Other values fail evaluator creation with [`ValueError::UnexpectedValue`]; pass
+    /// structured data as JSON text and parse it with `pkl:json` instead.
+    pub typed_properties: HashMap<String, Value>,
+    pub client_module_readers: Vec<Arc<dyn ModuleReader>>,
+    pub client_resource_readers: Vec<Arc<dyn ResourceReader>>,
+    /// Resource contents keyed by absolute URI, e.g. `config://db`, served to
+    /// `read(...)` without a [`ResourceReader`]. Each URI is allowed automatically.
+    /// A scheme should be served either here or by a reader, not both.
+    pub inline_resources: HashMap<String, Vec<u8>>,
 }
 
 impl Default for EvalOpts {
@@ -27,50 +141,501 @@ impl Default for EvalOpts {
         Self {
             allowed_modules: vec!["pkl:".to_string()],
             allowed_resources: vec![],
-            output_format: "pkl".to_string(),
+            output_format: Some(OutputFormat::Pkl),
             project: None,
+            module_paths: vec![],
+            http: None,
+            max_result_bytes: None,
+            max_nodes: None,
+            timeout: None,
+            cache_dir: None,
+            root_dir: None,
+            env: HashMap::new(),
+            properties: HashMap::new(),
+            typed_properties: HashMap::new(),
+            client_module_readers: vec![],
+            client_resource_readers: vec![],
+            inline_resources: HashMap::new(),
         }
     }
 }
 
-impl Evaluator {
-    pub fn new(proto: Protocol) -> Self {
-        Self {
-            proto,
-            request_id: 0,
+/// Renderer pkl uses for `output.text`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    /// Pkl itself, pkl's `pcf` format.
+    Pkl,
+    Json,
+    Jsonnet,
+    Yaml,
+    Plist,
+    Xml,
+    Properties,
+    Textproto,
+    /// Any other format name, passed to pkl as is.
+    Custom(String),
+}
+
+impl OutputFormat {
+    /// The name pkl expects for this format.
+    pub fn as_str(&self) -> &str {
+        match self {
+            OutputFormat::Pkl => "pcf",
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonnet => "jsonnet",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Plist => "plist",
+            OutputFormat::Xml => "xml",
+            OutputFormat::Properties => "properties",
+            OutputFormat::Textproto => "textproto",
+            OutputFormat::Custom(name) => name,
         }
     }
+}
 
-    fn gen_request_id(&mut self) -> u64 {
-        let request_id = self.request_id;
-        // This can overflow, but that's fine for our use case
-        self.request_id += 1;
-        request_id
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
     }
+}
 
-    #[instrument(skip(self, opts))]
-    pub fn eval(&mut self, opts: &EvalOpts, uri: Uri) -> Result<Option<Value>, Error> {
-        let request_id = self.gen_request_id();
-        let module_paths = [uri.to_string()];
+impl EvalOpts {
+    pub fn allowing_module(&self, pattern: impl Into<String>) -> EvalOpts {
+        let mut opts = self.clone();
+        opts.allowed_modules.push(pattern.into());
+        opts
+    }
 
-        let mut request = CreateEvaluatorRequest::default();
-        request.request_id = request_id;
-        request.allowed_modules = Some(&opts.allowed_modules);
-        request.allowed_resources = Some(&opts.allowed_resources);
-        request.output_format = Some(&opts.output_format);
+    pub fn allowing_resource(&self, pattern: impl Into<String>) -> EvalOpts {
+        let mut opts = self.clone();
+        opts.allowed_resources.push(pattern.into());
+        opts
+    }
 
-        if opts.project.is_some() {
-            request.project = opts.project.as_ref();
-        } else {
-            request.module_paths = Some(&module_paths);
+    /// Confines `file:` modules and resources to `dir`: sets `root_dir` and replaces
+    /// any `file:` allow patterns with one that only matches paths below `dir`.
+    pub fn file_root(&mut self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = fs::canonicalize(dir)?;
+        let pattern = format!("{}/", escape_pattern(&Uri::File(dir.clone()).to_string()));
+
+        for patterns in [&mut self.allowed_modules, &mut self.allowed_resources] {
+            patterns.retain(|p| !p.starts_with("file:"));
+            patterns.push(pattern.clone());
+        }
+
+        self.root_dir = Some(dir);
+
+        Ok(())
+    }
+
+    /// Copies the environment of the current process into `env`, keeping variables
+    /// that are already set there. Variables that aren't valid Unicode are skipped.
+    pub fn inherit_env(&mut self) {
+        for (name, value) in std::env::vars_os() {
+            if let (Ok(name), Ok(value)) = (name.into_string(), value.into_string()) {
+                self.env.entry(name).or_insert(value);
+            }
         }
+    }
+
+    /// Key identifying the result of evaluating `uri` with these options, for caches
+    /// that outlive the process: the URI (canonicalized for files) followed by
+    /// [`EvalOpts::create_options_hash`] in hex.
+    pub fn cache_key(&self, uri: &Uri) -> String {
+        let uri = match uri {
+            Uri::File(path) => Uri::File(fs::canonicalize(path).unwrap_or_else(|_| path.clone())),
+            uri => uri.clone(),
+        };
+
+        format!("{uri}#{:016x}", self.create_options_hash())
+    }
 
-        let mut response = self.proto.create_evaluator_request(request)?;
+    /// Hash of the options that are fixed when pkl creates an evaluator, so two
+    /// options with the same hash can share an evaluator.
+    ///
+    /// The hash is FNV-1a over the options, so it is stable across runs and Rust
+    /// versions. The order of allow-list patterns, environment variables, properties
+    /// and readers doesn't affect it. Readers are hashed by scheme, so compare them
+    /// with [`EvalOpts::same_readers`] too before sharing an evaluator.
+    pub fn create_options_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
 
+        hasher.write_sorted(self.allowed_modules.iter().map(String::as_bytes));
+        hasher.write_sorted(self.allowed_resources.iter().map(String::as_bytes));
+        hasher.write_optional(
+            self.output_format
+                .as_ref()
+                .map(|format| format.as_str().as_bytes()),
+        );
+        hasher.write_optional(
+            self.timeout_seconds()
+                .map(i64::to_le_bytes)
+                .as_ref()
+                .map(<[u8; 8]>::as_slice),
+        );
+        hasher.write_optional(
+            self.cache_dir
+                .as_deref()
+                .map(|dir| dir.as_os_str().as_encoded_bytes()),
+        );
+        hasher.write_optional(
+            self.root_dir
+                .as_deref()
+                .map(|dir| dir.as_os_str().as_encoded_bytes()),
+        );
+        hasher.write_optional(
+            self.project
+                .as_ref()
+                .map(|project| project.project_file_uri.to_string().into_bytes())
+                .as_deref(),
+        );
+        hasher.write_ordered(self.module_paths.iter().map(String::as_bytes));
+        hasher.write_optional(
+            self.http
+                .as_ref()
+                .and_then(|http| http.ca_certificates.as_deref()),
+        );
+        hasher.write_optional(
+            self.http
+                .as_ref()
+                .and_then(|http| http.proxy.as_ref())
+                .map(|proxy| format!("{:?}", (&proxy.address, &proxy.no_proxy)).into_bytes())
+                .as_deref(),
+        );
+        hasher.write_sorted(
+            self.env
+                .iter()
+                .map(|(name, value)| [name.as_bytes(), b"=", value.as_bytes()].concat()),
+        );
+        hasher.write_sorted(
+            self.properties
+                .iter()
+                .map(|(name, value)| [name.as_bytes(), b"=", value.as_bytes()].concat()),
+        );
+        hasher.write_sorted(
+            self.typed_properties
+                .iter()
+                .map(|(name, value)| format!("{name}={value:?}").into_bytes()),
+        );
+        hasher.write_sorted(
+            self.client_module_readers
+                .iter()
+                .map(|reader| reader.scheme().as_bytes()),
+        );
+        hasher.write_sorted(
+            self.client_resource_readers
+                .iter()
+                .map(|reader| reader.scheme().as_bytes()),
+        );
+        hasher.write_sorted(
+            self.inline_resources
+                .iter()
+                .map(|(uri, contents)| [uri.as_bytes(), b"=", contents.as_slice()].concat()),
+        );
+
+        hasher.0
+    }
+
+    /// Whether `self` and `other` have the very same reader instances, which
+    /// [`EvalOpts::create_options_hash`] can't tell apart from other readers of the
+    /// same schemes.
+    pub fn same_readers(&self, other: &EvalOpts) -> bool {
+        fn same<T: ?Sized>(a: &[Arc<T>], b: &[Arc<T>]) -> bool {
+            a.len() == b.len() && a.iter().all(|x| b.iter().any(|y| Arc::ptr_eq(x, y)))
+        }
+
+        same(&self.client_module_readers, &other.client_module_readers)
+            && same(
+                &self.client_resource_readers,
+                &other.client_resource_readers,
+            )
+    }
+
+    /// How long to wait for pkl's answer to an evaluation with these options.
+    fn response_timeout(&self) -> Option<Duration> {
+        self.timeout.map(|timeout| timeout + TIMEOUT_GRACE)
+    }
+
+    fn timeout_seconds(&self) -> Option<i64> {
+        self.timeout.map(|timeout| {
+            let seconds = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+            i64::try_from(seconds).unwrap_or(i64::MAX)
+        })
+    }
+
+    /// The error pkl reported for an evaluation, or its result decoded with these
+    /// options.
+    pub(crate) fn response_value(
+        &self,
+        mut response: EvaluateResponse,
+    ) -> Result<Option<Value>, Error> {
         if let Some(message) = response.error.take() {
             return Err(Error::Pkl(PklError::parse(message)));
         }
 
+        match response.result {
+            Some(result) => {
+                self.check_result_size(result.len())?;
+                Ok(Some(self.decode_result(&result)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn decode_result(&self, result: &[u8]) -> Result<Value, ValueError> {
+        Decoder::new(result).with_max_nodes(self.max_nodes).decode()
+    }
+
+    fn check_result_size(&self, size: usize) -> Result<(), Error> {
+        match self.max_result_bytes {
+            Some(limit) if size > limit => Err(Error::ResultTooLarge { size, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Module allow-list sent to pkl when evaluating `uri`, assembled the same way
+    /// whether or not a project or explicit patterns are present:
+    ///
+    /// 1. the explicit `allowed_modules`;
+    /// 2. with a project, `projectpackage:` for its remote dependencies and the
+    ///    directory of each local dependency;
+    /// 3. the scheme of `uri`, unless a pattern from 1. already targets that scheme,
+    ///    so restrictions such as [`EvalOpts::file_root`] aren't widened.
+    pub fn effective_allowed_modules(&self, uri: Option<&Uri>) -> Vec<String> {
+        let mut allowed = self.allowed_modules.clone();
+
+        if let Some(project) = &self.project {
+            allowed.push("projectpackage:".to_string());
+
+            for dependency in project.dependencies.values() {
+                if let ProjectDependency::Local(local) = dependency
+                    && let Uri::File(project_file) = &local.project_file_uri
+                    && let Some(dir) = project_file.parent()
+                {
+                    let dir = Uri::File(dir.to_path_buf()).to_string();
+                    allowed.push(format!("{}/", escape_pattern(&dir)));
+                }
+            }
+        }
+
+        if let Some(scheme) = uri.and_then(Uri::scheme)
+            && !scheme_allowed(&self.allowed_modules, scheme)
+        {
+            allowed.push(format!("{scheme}:"));
+        }
+
+        let mut seen = HashSet::new();
+        allowed.retain(|pattern| seen.insert(pattern.clone()));
+
+        allowed
+    }
+
+    /// `properties` merged with the rendered `typed_properties`.
+    fn effective_properties(&self) -> Result<HashMap<String, String>, ValueError> {
+        let mut properties = self.properties.clone();
+
+        for (name, value) in &self.typed_properties {
+            let text = match value {
+                Value::Null => {
+                    properties.remove(name);
+                    continue;
+                }
+                Value::String(s) => s.clone(),
+                Value::Int(n) => n.to_string(),
+                Value::Uint(n) => n.to_string(),
+                Value::Float(n) => pkl_float(*n),
+                Value::Bool(b) => b.to_string(),
+                Value::Duration { value, unit } => format!("{}.{unit}", pkl_float(*value)),
+                Value::DataSize { value, unit } => format!("{}.{unit}", pkl_float(*value)),
+                _ => return Err(ValueError::UnexpectedValue),
+            };
+
+            properties.insert(name.clone(), text);
+        }
+
+        Ok(properties)
+    }
+
+    /// Resource allow-list sent to pkl: the explicit `allowed_resources` plus the exact
+    /// URI of every inline resource.
+    fn effective_allowed_resources(&self) -> Vec<String> {
+        let mut allowed = self.allowed_resources.clone();
+
+        for uri in self.inline_resources.keys() {
+            allowed.push(format!("{}$", escape_pattern(uri)));
+        }
+
+        allowed
+    }
+
+    /// The registered resource readers followed by one reader per scheme of
+    /// `inline_resources`.
+    fn resource_readers(&self) -> Vec<Arc<dyn ResourceReader>> {
+        let mut readers = self.client_resource_readers.clone();
+
+        if self.inline_resources.is_empty() {
+            return readers;
+        }
+
+        let resources = Arc::new(self.inline_resources.clone());
+        let schemes = self
+            .inline_resources
+            .keys()
+            .filter_map(|uri| uri.split_once(':').map(|(scheme, _)| scheme))
+            .collect::<BTreeSet<_>>();
+
+        for scheme in schemes {
+            readers.push(Arc::new(InlineResources {
+                scheme: scheme.to_string(),
+                resources: Arc::clone(&resources),
+            }));
+        }
+
+        readers
+    }
+
+    /// Checks that the scheme of every registered reader is permitted by the
+    /// corresponding allow-list, and that every scheme an allow-list names is served
+    /// either by pkl itself or by a registered reader, so a mistake is reported up
+    /// front instead of surfacing halfway through evaluation.
+    ///
+    /// Allow-list entries are regular expressions, matched against the start of a URI
+    /// like pkl does, so `^https:` and `file:.*` allow their schemes. Entries that
+    /// don't start with a literal scheme, such as `.*`, are not checked for a reader.
+    pub fn validate_readers(&self) -> Result<(), Error> {
+        for reader in &self.client_module_readers {
+            if !scheme_allowed(&self.allowed_modules, reader.scheme()) {
+                return Err(Error::ReaderNotAllowed {
+                    kind: "module",
+                    scheme: reader.scheme().to_string(),
+                });
+            }
+        }
+
+        for reader in &self.client_resource_readers {
+            if !scheme_allowed(&self.allowed_resources, reader.scheme()) {
+                return Err(Error::ReaderNotAllowed {
+                    kind: "resource",
+                    scheme: reader.scheme().to_string(),
+                });
+            }
+        }
+
+        let module_schemes = self
+            .client_module_readers
+            .iter()
+            .map(|reader| reader.scheme())
+            .chain(BUILTIN_MODULE_SCHEMES.iter().copied())
+            .collect::<HashSet<_>>();
+
+        if let Some(scheme) = unserved_scheme(&self.allowed_modules, &module_schemes) {
+            return Err(Error::ReaderMissing {
+                kind: "module",
+                scheme: scheme.to_string(),
+            });
+        }
+
+        let resource_schemes = self
+            .client_resource_readers
+            .iter()
+            .map(|reader| reader.scheme())
+            .chain(
+                self.inline_resources
+                    .keys()
+                    .filter_map(|uri| uri.split_once(':').map(|(scheme, _)| scheme)),
+            )
+            .chain(BUILTIN_RESOURCE_SCHEMES.iter().copied())
+            .collect::<HashSet<_>>();
+
+        if let Some(scheme) = unserved_scheme(&self.allowed_resources, &resource_schemes) {
+            return Err(Error::ReaderMissing {
+                kind: "resource",
+                scheme: scheme.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The parts of a `CreateEvaluatorRequest` derived from [`EvalOpts`], owned so the
+/// request can borrow them. Shared by the blocking and async evaluators.
+pub(crate) struct EvaluatorSetup<'a> {
+    opts: &'a EvalOpts,
+    module_paths: Vec<String>,
+    allowed_modules: Vec<String>,
+    allowed_resources: Vec<String>,
+    properties: HashMap<String, String>,
+    resource_readers: Vec<Arc<dyn ResourceReader>>,
+    resource_specs: Vec<ClientResourceReader>,
+    module_specs: Vec<ClientModuleReader>,
+}
+
+impl<'a> EvaluatorSetup<'a> {
+    pub(crate) fn new(opts: &'a EvalOpts, uri: Option<&Uri>) -> Result<Self, Error> {
+        opts.validate_readers()?;
+
+        let module_paths = if !opts.module_paths.is_empty() {
+            opts.module_paths.clone()
+        } else if opts.project.is_none() {
+            uri.map(Uri::to_string).into_iter().collect()
+        } else {
+            vec![]
+        };
+        let resource_readers = opts.resource_readers();
+        let resource_specs = resource_readers
+            .iter()
+            .map(|reader| reader::resource_spec(reader.as_ref()))
+            .collect();
+        let module_specs = opts
+            .client_module_readers
+            .iter()
+            .map(|reader| reader::module_spec(reader.as_ref()))
+            .collect();
+
+        Ok(Self {
+            opts,
+            module_paths,
+            allowed_modules: opts.effective_allowed_modules(uri),
+            allowed_resources: opts.effective_allowed_resources(),
+            properties: opts.effective_properties()?,
+            resource_readers,
+            resource_specs,
+            module_specs,
+        })
+    }
+
+    pub(crate) fn request(&self, request_id: u64) -> CreateEvaluatorRequest<'_> {
+        let opts = self.opts;
+
+        CreateEvaluatorRequest {
+            request_id,
+            allowed_modules: Some(&self.allowed_modules),
+            allowed_resources: Some(&self.allowed_resources),
+            output_format: opts.output_format.as_ref().map(OutputFormat::as_str),
+            client_module_readers: Some(&self.module_specs),
+            client_resource_readers: Some(&self.resource_specs),
+            root_dir: opts.root_dir.as_deref().and_then(Path::to_str),
+            cache_dir: opts.cache_dir.as_deref().and_then(Path::to_str),
+            env: (!opts.env.is_empty()).then_some(&opts.env),
+            timeout_seconds: opts.timeout_seconds(),
+            properties: (!self.properties.is_empty()).then(|| self.properties.clone()),
+            project: opts.project.as_ref(),
+            http: opts.http.as_ref(),
+            module_paths: (!self.module_paths.is_empty()).then_some(self.module_paths.as_slice()),
+        }
+    }
+
+    /// The id of the evaluator pkl created in answer to the request with
+    /// `request_id`, or the reason it refused to.
+    pub(crate) fn evaluator_id(
+        mut response: CreateEvaluatorResponse,
+        request_id: u64,
+    ) -> Result<i64, Error> {
+        if let Some(message) = response.error.take() {
+            return Err(Error::from_create_error(message));
+        }
+
         if response.request_id != request_id {
             return Err(Error::InvalidRequestId {
                 expected: request_id,
@@ -78,24 +643,1167 @@ impl Evaluator {
             });
         }
 
-        let mut request = EvaluateRequest::default();
-        request.request_id = request_id;
-        request.evaluator_id = response.evaluator_id.unwrap_or_default();
-        request.module_uri = uri;
-        request.expr = None;
+        Ok(response.evaluator_id.unwrap_or_default())
+    }
+
+    /// Serves the readers advertised in the request for the created evaluator.
+    pub(crate) fn register_readers(self, evaluator_id: i64, requests: &mut ServerRequests) {
+        if !self.resource_readers.is_empty() {
+            requests.register_resource_readers(evaluator_id, self.resource_readers);
+        }
+
+        if !self.opts.client_module_readers.is_empty() {
+            requests.register_module_readers(evaluator_id, self.opts.client_module_readers.clone());
+        }
+    }
+}
+
+/// 64-bit FNV-1a. Fields are length-prefixed so adjacent ones can't run together.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in (bytes.len() as u64).to_le_bytes().iter().chain(bytes) {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_optional(&mut self, bytes: Option<&[u8]>) {
+        match bytes {
+            Some(bytes) => {
+                self.write(b"some");
+                self.write(bytes);
+            }
+            None => self.write(b"none"),
+        }
+    }
+
+    fn write_sorted<T: AsRef<[u8]> + Ord>(&mut self, items: impl Iterator<Item = T>) {
+        let mut items = items.collect::<Vec<_>>();
+        items.sort();
+
+        self.write_ordered(items.into_iter());
+    }
+
+    fn write_ordered<T: AsRef<[u8]>>(&mut self, items: impl ExactSizeIterator<Item = T>) {
+        self.write(&(items.len() as u64).to_le_bytes());
+
+        for item in items {
+            self.write(item.as_ref());
+        }
+    }
+}
+
+/// `n` as a Pkl float literal, which spells out the non-finite ones.
+fn pkl_float(n: f64) -> String {
+    match n {
+        f64::INFINITY => "Infinity".to_string(),
+        f64::NEG_INFINITY => "-Infinity".to_string(),
+        n if n.is_nan() => "NaN".to_string(),
+        n => n.to_string(),
+    }
+}
+
+fn escape_pattern(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if r"\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Whether one of `patterns` allows URIs of `scheme`. Like pkl, patterns are regular
+/// expressions matched against the start of a URI: a pattern allows the scheme when
+/// it starts with it literally, e.g. `file:///home/`, or, with the `regex` feature,
+/// when it matches `<scheme>:`.
+fn scheme_allowed(patterns: &[String], scheme: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| pattern_scheme(pattern) == Some(scheme) || pattern_matches(pattern, scheme))
+}
+
+#[cfg(feature = "regex")]
+fn pattern_matches(pattern: &str, scheme: &str) -> bool {
+    Regex::new(&format!("^(?:{pattern})")).is_ok_and(|regex| regex.is_match(&format!("{scheme}:")))
+}
+
+/// Without the `regex` feature, only patterns that start with the scheme count.
+#[cfg(not(feature = "regex"))]
+fn pattern_matches(_pattern: &str, _scheme: &str) -> bool {
+    false
+}
+
+/// The scheme a pattern starts with literally, e.g. `https` for `^https://`.
+fn pattern_scheme(pattern: &str) -> Option<&str> {
+    let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+    let (scheme, _) = pattern.split_once(':')?;
+
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+
+    valid.then_some(scheme)
+}
+
+/// The first scheme named by `patterns` that isn't in `served`.
+fn unserved_scheme<'p>(patterns: &'p [String], served: &HashSet<&str>) -> Option<&'p str> {
+    patterns
+        .iter()
+        .filter_map(|pattern| pattern_scheme(pattern))
+        .find(|scheme| !served.contains(scheme))
+}
+
+impl Evaluator {
+    pub fn new(proto: Protocol) -> Self {
+        Self {
+            proto,
+            request_id: AtomicU64::new(0),
+        }
+    }
+
+    /// A request id no other request of this evaluator uses, so responses can be
+    /// matched to their requests. Wraps around on overflow, which is fine for our use
+    /// case.
+    fn gen_request_id(&self) -> u64 {
+        self.request_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn set_observer(&mut self, observer: impl MessageObserver + 'static) {
+        self.proto.set_observer(observer);
+    }
+
+    /// Passes the `trace()` output and warnings of evaluated modules to `f`, e.g. to
+    /// forward them into the application's own logging. Without a callback they are
+    /// emitted through `tracing`.
+    pub fn on_log(&mut self, f: impl FnMut(LogEvent) + Send + 'static) {
+        self.proto.on_log(f);
+    }
+
+    pub fn close(self) -> Result<(), Error> {
+        self.proto.close()
+    }
+
+    /// Creates an evaluator inside the pkl process that is reused by every
+    /// evaluation of the returned [`Session`], avoiding the setup cost per module.
+    #[instrument(skip_all)]
+    pub fn create_session(&mut self, opts: &EvalOpts) -> Result<Session<'_>, Error> {
+        let evaluator_id = self.create_evaluator(opts, None)?;
+        Ok(Session::new(self, opts.clone(), evaluator_id))
+    }
+
+    #[instrument(skip(self, opts))]
+    pub fn eval(&mut self, opts: &EvalOpts, uri: Uri) -> Result<Option<Value>, Error> {
+        self.eval_inner(opts, uri, None)
+    }
+
+    /// Like [`Evaluator::eval`], but deserializes the result into `T`. A module
+    /// without a result fails with [`Error::EmptyResult`], and a result that doesn't
+    /// match the shape of `T` with [`Error::Value`].
+    #[instrument(skip(self, opts))]
+    pub fn eval_into<T: DeserializeOwned>(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+    ) -> Result<T, Error> {
+        let value = self.eval(opts, uri)?.ok_or(Error::EmptyResult)?;
+        Ok(T::deserialize(&value)?)
+    }
+
+    /// Evaluates `uri` with a dedicated `pkl server` process running in `dir`, leaving
+    /// the host process's working directory untouched.
+    ///
+    /// The working directory of a process is fixed when it is spawned, so this pays
+    /// the start-up cost of a fresh process on every call. When evaluating many
+    /// modules in the same directory, spawn a [`Protocol`] with
+    /// [`ProtocolBuilder::current_dir`](crate::protocol::ProtocolBuilder::current_dir)
+    /// once and reuse it instead.
+    #[instrument(skip(opts, dir), fields(dir = ?dir.as_ref()))]
+    pub fn eval_in_dir(
+        opts: &EvalOpts,
+        dir: impl AsRef<Path>,
+        uri: Uri,
+    ) -> Result<Option<Value>, Error> {
+        let proto = Protocol::builder().current_dir(dir.as_ref()).spawn()?;
+        let mut evaluator = Evaluator::new(proto);
+        let result = evaluator.eval(opts, uri);
+        evaluator.close()?;
+
+        result
+    }
+
+    #[instrument(skip(self, opts))]
+    pub fn eval_expr(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+        expr: &str,
+    ) -> Result<Option<Value>, Error> {
+        self.eval_inner(opts, uri, Some(expr))
+    }
+
+    /// Evaluates `uri` and passes the result to `expected` before handing it back,
+    /// e.g. to check a `schemaVersion` property before the value is used. A rejection
+    /// is returned as [`Error::SchemaMismatch`] with the predicate's message.
+    #[instrument(skip(self, opts, expected))]
+    pub fn eval_checked(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+        expected: impl Fn(&Value) -> Result<(), String>,
+    ) -> Result<Value, Error> {
+        let value = self.eval(opts, uri)?.ok_or(Error::EmptyResult)?;
+        expected(&value).map_err(Error::SchemaMismatch)?;
+
+        Ok(value)
+    }
+
+    /// Evaluates a validation module and splits its outcome into the validated value
+    /// or the list of validation messages.
+    ///
+    /// The module at `uri` must declare:
+    ///
+    /// ```pkl
+    /// valid: Boolean
+    /// errors: Listing<String>
+    /// value: Any
+    /// ```
+    ///
+    /// When `valid` is `true` this returns `Ok(Ok(value))`, otherwise
+    /// `Ok(Err(errors))`. A module that doesn't follow this shape is an
+    /// [`ValueError::UnexpectedValue`] error.
+    #[instrument(skip(self, opts))]
+    pub fn eval_validation(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+    ) -> Result<Result<Value, Vec<String>>, Error> {
+        let Some(Value::Object(mut object)) = self.eval(opts, uri)? else {
+            return Err(ValueError::UnexpectedValue.into());
+        };
+
+        let mut take = |name| {
+            object
+                .properties
+                .shift_remove(name)
+                .ok_or(ValueError::UnexpectedValue)
+        };
+
+        match (take("valid")?, take("errors")?) {
+            (Value::Bool(true), _) => Ok(Ok(take("value")?)),
+            (Value::Bool(false), Value::Array(errors) | Value::Listing(errors)) => Ok(Err(errors
+                .into_iter()
+                .map(String::try_from)
+                .collect::<Result<_, _>>()?)),
+            _ => Err(ValueError::UnexpectedValue.into()),
+        }
+    }
+
+    /// Evaluates `expr` against the module at `uri` with `bindings` supplied as external
+    /// properties, so the expression can read them with `read("prop:<name>")`.
+    /// Bindings are rendered as text like [`EvalOpts::typed_properties`].
+    ///
+    /// Only the bindings are made readable: `prop:` resources are allowed for their
+    /// names alone, so the other [`EvalOpts::properties`] stay out of reach unless
+    /// `opts` already allows `prop:`.
+    #[instrument(skip(self, opts, bindings))]
+    pub fn eval_expr_with(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+        expr: &str,
+        bindings: HashMap<String, Value>,
+    ) -> Result<Option<Value>, Error> {
+        let names: Vec<_> = bindings.keys().map(|name| escape_pattern(name)).collect();
+        let mut opts = opts.allowing_resource(format!("prop:(?:{})$", names.join("|")));
+        opts.typed_properties.extend(bindings);
+
+        self.eval_inner(&opts, uri, Some(expr))
+    }
+
+    /// Evaluates `uri` and returns the server's response as-is, without turning an
+    /// error into `Err` or decoding the result.
+    #[instrument(skip(self, opts))]
+    pub fn evaluate_raw(&mut self, opts: &EvalOpts, uri: Uri) -> Result<EvaluateResponse, Error> {
+        self.with_evaluator(opts, uri, |this, evaluator_id, uri| {
+            this.send_evaluate(opts, evaluator_id, uri, None, None)
+        })
+    }
+
+    /// Like [`Evaluator::eval`], but stores the raw result bytes in `buf`, reusing its
+    /// allocation across calls instead of allocating a new buffer per evaluation.
+    #[instrument(skip(self, opts, buf))]
+    pub fn eval_into_buf(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<Value>, Error> {
+        self.with_evaluator(opts, uri, |this, evaluator_id, uri| {
+            this.evaluate_into_buf(opts, evaluator_id, uri, buf)
+        })
+    }
+
+    fn evaluate_into_buf(
+        &mut self,
+        opts: &EvalOpts,
+        evaluator_id: i64,
+        uri: Uri,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<Value>, Error> {
+        let request = EvaluateRequest {
+            request_id: self.gen_request_id(),
+            evaluator_id,
+            module_uri: uri,
+            ..Default::default()
+        };
 
-        let mut response = self.proto.evaluate_request(request)?;
+        let (mut response, has_result) = self
+            .proto
+            .with_read_timeout(opts.response_timeout(), |proto| {
+                proto.evaluate_request_into(request, buf)
+            })?;
 
         if let Some(message) = response.error.take() {
             return Err(Error::Pkl(PklError::parse(message)));
         }
 
-        match response.result {
-            Some(mut result) => {
-                let mut decoder = Decoder::new(Cursor::new(&mut result));
-                Ok(Some(decoder.decode()?))
+        if !has_result {
+            return Ok(None);
+        }
+
+        opts.check_result_size(buf.len())?;
+
+        Ok(Some(opts.decode_result(buf)?))
+    }
+
+    /// Reads Pkl source from `reader` (e.g. stdin) and evaluates it as a module named
+    /// `repl:<name>`. Input larger than [`MAX_MODULE_TEXT_LEN`] bytes is rejected.
+    #[instrument(skip(self, opts, reader))]
+    pub fn eval_reader(
+        &mut self,
+        opts: &EvalOpts,
+        name: &str,
+        reader: impl Read,
+    ) -> Result<Option<Value>, Error> {
+        let mut text = Vec::new();
+        reader
+            .take(MAX_MODULE_TEXT_LEN as u64 + 1)
+            .read_to_end(&mut text)?;
+
+        if text.len() > MAX_MODULE_TEXT_LEN {
+            return Err(Error::ModuleTextTooLarge {
+                limit: MAX_MODULE_TEXT_LEN,
+            });
+        }
+
+        let text = String::from_utf8(text).map_err(Error::InvalidModuleText)?;
+        self.eval_text(opts, name, &text)
+    }
+
+    /// Evaluates a small module with `opts` that reports what pkl itself resolved:
+    /// the pkl version, runtime, operating system and the effective output format.
+    ///
+    /// Settings that aren't visible to Pkl code, such as the cache directory, proxy
+    /// or allow-lists, can't be reported; a permission error here does however show
+    /// that `pkl:` or `prop:` access is restricted.
+    #[instrument(skip(self, opts))]
+    pub fn effective_settings(&mut self, opts: &EvalOpts) -> Result<Value, Error> {
+        const SETTINGS: &str = r#"
+import "pkl:platform"
+
+pklVersion = platform.current.language.version.toString()
+runtime = platform.current.runtime.name
+runtimeVersion = platform.current.runtime.version.toString()
+operatingSystem = platform.current.operatingSystem.name
+outputFormat = read?("prop:pkl.outputFormat")
+"#;
+
+        let opts = opts.allowing_resource("prop:pkl.outputFormat");
+        self.eval_text(&opts, "settings", SETTINGS)?
+            .ok_or(Error::EmptyResult)
+    }
+
+    /// Describes the module at `uri` (its name, doc comment and declared properties
+    /// with their types) through `pkl:reflect`, without evaluating its output.
+    #[instrument(skip(self, opts))]
+    pub fn module_info(&mut self, opts: &EvalOpts, uri: Uri) -> Result<ModuleInfo, Error> {
+        let text = reflect::module_info_text(&uri);
+        let info = self
+            .eval_text(opts, "module-info", &text)?
+            .ok_or(Error::EmptyResult)?;
+
+        Ok(info.try_into()?)
+    }
+
+    /// Evaluates the module at `uri` and also reports where each of its top-level
+    /// properties is declared, keyed by the same paths as [`Value::flatten`].
+    ///
+    /// Pkl only exposes source positions for declared properties through
+    /// `pkl:reflect`, so the map covers the properties the module itself declares:
+    /// members of nested objects, listings and mappings, and properties inherited
+    /// through `amends`/`extends`, have no entry. Look up the longest map key that
+    /// prefixes a flattened path to attribute a nested leaf to its top-level property.
+    #[instrument(skip(self, opts))]
+    pub fn eval_with_source_map(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+    ) -> Result<(Value, BTreeMap<String, SourceLocation>), Error> {
+        let text = reflect::locations_text(&uri);
+        let value = self.eval(opts, uri)?.ok_or(Error::EmptyResult)?;
+        let locations = self
+            .eval_text(opts, "source-map", &text)?
+            .ok_or(Error::EmptyResult)?;
+
+        let source_map = match &value {
+            Value::Object(object) => reflect::parse_locations(locations)?
+                .into_iter()
+                .filter(|(name, _)| object.properties.contains_key(name))
+                .map(|(name, location)| (name_segment(&name), location))
+                .collect(),
+            _ => BTreeMap::new(),
+        };
+
+        Ok((value, source_map))
+    }
+
+    fn eval_text(
+        &mut self,
+        opts: &EvalOpts,
+        name: &str,
+        text: &str,
+    ) -> Result<Option<Value>, Error> {
+        let opts = opts.allowing_module("repl:");
+        let uri = Uri::Url(format!("repl:{name}"));
+
+        self.with_evaluator(&opts, uri, |this, evaluator_id, uri| {
+            this.evaluate(&opts, evaluator_id, uri, Some(text), None)
+        })
+    }
+
+    /// Evaluates `expr`, which must yield a `Listing` or `List` (e.g. `output.value` or
+    /// a property name), one element at a time, so an element that fails to evaluate
+    /// or decode doesn't lose the others.
+    ///
+    /// The result has one entry per element, in order: `Ok` with the element's value,
+    /// or `Err` with the [`Error::Pkl`] or [`Error::Value`] it failed with. Pkl
+    /// evaluates elements lazily, so counting them doesn't force any element. Failing
+    /// to evaluate the listing itself, and I/O or protocol errors, fail the whole call.
+    #[instrument(skip(self, opts))]
+    pub fn eval_elements(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+        expr: &str,
+    ) -> Result<Vec<Result<Value, Error>>, Error> {
+        self.with_evaluator(opts, uri, |this, evaluator_id, uri| {
+            this.evaluate_elements(opts, evaluator_id, uri, expr)
+        })
+    }
+
+    fn evaluate_elements(
+        &mut self,
+        opts: &EvalOpts,
+        evaluator_id: i64,
+        uri: Uri,
+        expr: &str,
+    ) -> Result<Vec<Result<Value, Error>>, Error> {
+        let len: u64 = self
+            .evaluate(
+                opts,
+                evaluator_id,
+                uri.clone(),
+                None,
+                Some(&format!("({expr}).length")),
+            )?
+            .ok_or(Error::EmptyResult)?
+            .try_into()?;
+
+        let mut elements = Vec::new();
+
+        for i in 0..len {
+            let element = format!("({expr})[{i}]");
+
+            match self.evaluate(opts, evaluator_id, uri.clone(), None, Some(&element)) {
+                Ok(value) => elements.push(value.ok_or(Error::EmptyResult)),
+                Err(err @ (Error::Pkl(_) | Error::Value(_))) => elements.push(Err(err)),
+                Err(err) => return Err(err),
             }
-            None => Ok(None),
         }
+
+        Ok(elements)
+    }
+
+    /// Evaluates both `output.value` and `output.files` of the module at `uri` using a
+    /// single evaluator, so both results come from the same evaluation. File contents
+    /// are the rendered `text` of each entry in `output.files`.
+    #[instrument(skip(self, opts))]
+    pub fn eval_value_and_files(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+    ) -> Result<(Value, HashMap<String, Vec<u8>>), Error> {
+        let (value, files) = self.with_evaluator(opts, uri, |this, evaluator_id, uri| {
+            let value = this
+                .evaluate(opts, evaluator_id, uri.clone(), None, Some("output.value"))?
+                .ok_or(Error::EmptyResult)?;
+            let files = this.evaluate(
+                opts,
+                evaluator_id,
+                uri,
+                None,
+                Some("output.files?.toMap()?.mapValues((_, file) -> file.text)"),
+            )?;
+
+            Ok((value, files))
+        })?;
+
+        let files = match files {
+            None | Some(Value::Null) => HashMap::new(),
+            Some(Value::Map(entries)) => entries
+                .into_iter()
+                .map(|(name, text)| {
+                    let name: String = name.try_into()?;
+                    let text: String = text.try_into()?;
+                    Ok((name, text.into_bytes()))
+                })
+                .collect::<Result<_, ValueError>>()?,
+            Some(_) => return Err(ValueError::UnexpectedValue.into()),
+        };
+
+        Ok((value, files))
+    }
+
+    /// Evaluates every module under `dir` whose path relative to `dir` matches `glob`,
+    /// usually [`DEFAULT_MODULE_GLOB`], returning each module's path with its result.
+    ///
+    /// Only files with a `.pkl` extension are evaluated, so `PklProject` files and
+    /// other files matched by a broad pattern are skipped. Paths are absolute and
+    /// sorted. All modules share one evaluator created with `opts`; an invalid
+    /// pattern or failing to create the evaluator fails the whole call, while errors
+    /// reading a subdirectory are reported in place of that entry's result.
+    #[instrument(skip(self, opts, dir), fields(dir = ?dir.as_ref()))]
+    pub fn eval_dir(
+        &mut self,
+        opts: &EvalOpts,
+        dir: impl AsRef<Path>,
+        glob: &str,
+    ) -> Result<DirResults, Error> {
+        let dir = fs::canonicalize(dir)?;
+        let pattern = Path::new(&glob::Pattern::escape(&dir.to_string_lossy())).join(glob);
+        let entries = glob::glob(&pattern.to_string_lossy())?
+            .filter(|entry| {
+                entry.as_ref().map_or(true, |path| {
+                    path.is_file() && path.extension().is_some_and(|ext| ext == "pkl")
+                })
+            })
+            .collect::<Vec<_>>();
+
+        self.with_evaluator(opts, Uri::File(dir), |this, evaluator_id, _| {
+            Ok(entries
+                .into_iter()
+                .map(|entry| match entry {
+                    Ok(path) => {
+                        let uri = Uri::File(path.clone());
+                        (path, this.evaluate(opts, evaluator_id, uri, None, None))
+                    }
+                    Err(err) => (err.path().to_path_buf(), Err(Error::IO(err.into()))),
+                })
+                .collect())
+        })
+    }
+
+    /// Evaluates each of `uris` in turn, sending every result to `tx` as soon as it is
+    /// available. A bounded channel makes evaluation wait for the receiver to catch
+    /// up. Stops early once the receiver is dropped.
+    #[instrument(skip_all)]
+    pub fn eval_to_channel(
+        &mut self,
+        opts: &EvalOpts,
+        uris: impl IntoIterator<Item = Uri>,
+        tx: &SyncSender<(Uri, Result<Option<Value>, Error>)>,
+    ) {
+        for uri in uris {
+            let result = self.eval(opts, uri.clone());
+
+            if tx.send((uri, result)).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Returns the URIs of all modules that `uri` transitively imports, amends or
+    /// extends, excluding the standard library (`pkl:`).
+    ///
+    /// The graph is read through `pkl:reflect`: every module in the closure is
+    /// evaluated once with an expression listing its resolved imports and supermodule.
+    /// Imported modules must therefore be allowed by `opts` as well.
+    #[instrument(skip(self, opts))]
+    pub fn dependencies(&mut self, opts: &EvalOpts, uri: Uri) -> Result<Vec<Uri>, Error> {
+        const IMPORTS: &str = "let (m = import(\"pkl:reflect\").Module(module)) \
+            (m.imports.values + List(m.supermodule?.uri).filterNonNull())";
+
+        self.with_evaluator(opts, uri, |this, evaluator_id, uri| {
+            let mut seen = HashSet::from([uri.to_string()]);
+            let mut queue = VecDeque::from([uri]);
+            let mut dependencies = vec![];
+
+            while let Some(module) = queue.pop_front() {
+                let imports =
+                    match this.evaluate(opts, evaluator_id, module, None, Some(IMPORTS))? {
+                        Some(Value::Array(imports)) => imports,
+                        _ => return Err(ValueError::UnexpectedValue.into()),
+                    };
+
+                for import in imports {
+                    let import: String = import.try_into()?;
+
+                    if import.starts_with("pkl:") || !seen.insert(import.clone()) {
+                        continue;
+                    }
+
+                    let import = Uri::from(import);
+                    queue.push_back(import.clone());
+                    dependencies.push(import);
+                }
+            }
+
+            Ok(dependencies)
+        })
+    }
+
+    fn eval_inner(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+        expr: Option<&str>,
+    ) -> Result<Option<Value>, Error> {
+        self.with_evaluator(opts, uri, |this, evaluator_id, uri| {
+            this.evaluate(opts, evaluator_id, uri, None, expr)
+        })
+    }
+
+    /// Creates an evaluator for `uri`, runs `f` with it and closes it again, so
+    /// one-off evaluations don't leave evaluators behind in the pkl process.
+    ///
+    /// The evaluator is closed whether or not `f` succeeds, including when decoding
+    /// its result fails. When `f` fails, a failure to close is ignored in favour of
+    /// the original error.
+    fn with_evaluator<T>(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Uri,
+        f: impl FnOnce(&mut Self, i64, Uri) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let evaluator_id = self.create_evaluator(opts, Some(&uri))?;
+        let result = f(self, evaluator_id, uri);
+        let closed = self.close_evaluator(evaluator_id);
+
+        let value = result?;
+        closed?;
+
+        Ok(value)
+    }
+
+    pub(crate) fn create_evaluator(
+        &mut self,
+        opts: &EvalOpts,
+        uri: Option<&Uri>,
+    ) -> Result<i64, Error> {
+        let setup = EvaluatorSetup::new(opts, uri)?;
+        let request_id = self.gen_request_id();
+
+        let response = self
+            .proto
+            .create_evaluator_request(setup.request(request_id))?;
+        let evaluator_id = EvaluatorSetup::evaluator_id(response, request_id)?;
+        setup.register_readers(evaluator_id, self.proto.requests_mut());
+
+        Ok(evaluator_id)
+    }
+
+    fn send_evaluate(
+        &mut self,
+        opts: &EvalOpts,
+        evaluator_id: i64,
+        uri: Uri,
+        module_text: Option<&str>,
+        expr: Option<&str>,
+    ) -> Result<EvaluateResponse, Error> {
+        let request = EvaluateRequest {
+            request_id: self.gen_request_id(),
+            evaluator_id,
+            module_uri: uri,
+            module_text,
+            expr,
+        };
+
+        self.proto
+            .with_read_timeout(opts.response_timeout(), |proto| {
+                proto.evaluate_request(request)
+            })
+    }
+
+    pub(crate) fn close_evaluator(&mut self, evaluator_id: i64) -> Result<(), Error> {
+        self.proto.close_evaluator(CloseEvaluator { evaluator_id })
+    }
+
+    pub(crate) fn evaluate(
+        &mut self,
+        opts: &EvalOpts,
+        evaluator_id: i64,
+        uri: Uri,
+        module_text: Option<&str>,
+        expr: Option<&str>,
+    ) -> Result<Option<Value>, Error> {
+        let response = self.send_evaluate(opts, evaluator_id, uri, module_text, expr)?;
+        opts.response_value(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use regex::Regex;
+    use serde::{Deserialize, de::IgnoredAny};
+
+    use super::*;
+    use crate::{
+        mock::{self, CREATE_EVALUATE, EVALUATE_WITH_LOGS, MockProtocol},
+        observer::LogLevel,
+        protocol::Message,
+        value,
+    };
+
+    /// The parts of a sent `CreateEvaluatorRequest` the tests look at.
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct SentCreate {
+        properties: Option<HashMap<String, String>>,
+        root_dir: Option<String>,
+        allowed_modules: Option<Vec<String>>,
+        allowed_resources: Option<Vec<String>>,
+        output_format: Option<String>,
+    }
+
+    /// The parts of a sent `CloseEvaluator` the tests look at.
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct SentClose {
+        evaluator_id: i64,
+    }
+
+    fn app() -> Uri {
+        Uri::File("/app.pkl".into())
+    }
+
+    /// Server output creating evaluator `evaluator_id` for `request_id` and answering
+    /// the next request with `key` (`result` or `error`) set to `value`.
+    fn evaluation(request_id: i32, evaluator_id: i32, key: &str, value: Value) -> Vec<u8> {
+        let created = value!({ "requestId" => request_id, "evaluatorId" => evaluator_id });
+        let evaluated = value!({
+            "requestId" => (request_id + 1),
+            "evaluatorId" => evaluator_id,
+            key => value,
+        });
+
+        let mut transcript = mock::message(CreateEvaluatorResponse::CODE, created).unwrap();
+        transcript.extend(mock::message(EvaluateResponse::CODE, evaluated).unwrap());
+        transcript
+    }
+
+    fn closed_evaluators(mock: &MockProtocol) -> Vec<i64> {
+        mock.sent_messages::<SentClose>(CloseEvaluator::CODE)
+            .unwrap()
+            .into_iter()
+            .map(|close| close.evaluator_id)
+            .collect()
+    }
+
+    #[test]
+    fn properties_are_sent_in_create_request() {
+        let (proto, mock) = MockProtocol::new(CREATE_EVALUATE);
+        let mut evaluator = Evaluator::new(proto);
+        let mut opts = EvalOpts::default();
+        opts.properties
+            .insert("env".to_string(), "staging".to_string());
+
+        evaluator.eval(&opts, app()).unwrap();
+
+        let sent = mock
+            .sent_messages::<SentCreate>(CreateEvaluatorRequest::CODE)
+            .unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].properties,
+            Some(HashMap::from([("env".to_string(), "staging".to_string())]))
+        );
+    }
+
+    #[test]
+    fn non_finite_floats_render_as_pkl_literals() {
+        let mut opts = EvalOpts::default();
+        opts.typed_properties.extend([
+            ("nan".to_string(), value!(f64::NAN)),
+            ("max".to_string(), value!(f64::INFINITY)),
+            ("min".to_string(), value!(f64::NEG_INFINITY)),
+            ("half".to_string(), value!(0.5)),
+        ]);
+
+        let properties = opts.effective_properties().unwrap();
+
+        assert_eq!(properties["nan"], "NaN");
+        assert_eq!(properties["max"], "Infinity");
+        assert_eq!(properties["min"], "-Infinity");
+        assert_eq!(properties["half"], "0.5");
+    }
+
+    #[test]
+    fn logs_before_the_response_reach_on_log() {
+        let (proto, _mock) = MockProtocol::new(EVALUATE_WITH_LOGS);
+        let mut evaluator = Evaluator::new(proto);
+        let levels = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&levels);
+        evaluator.on_log(move |event| seen.lock().unwrap().push(event.level));
+
+        let value = evaluator.eval(&EvalOpts::default(), app()).unwrap();
+
+        assert!(value.is_some());
+        assert_eq!(*levels.lock().unwrap(), [LogLevel::Trace, LogLevel::Warn]);
+    }
+
+    #[test]
+    fn bindings_are_the_only_readable_properties() {
+        let (proto, mock) = MockProtocol::new(CREATE_EVALUATE);
+        let mut evaluator = Evaluator::new(proto);
+        let mut opts = EvalOpts::default();
+        opts.properties
+            .insert("secret".to_string(), "hunter2".to_string());
+        let bindings = HashMap::from([
+            ("user.name".to_string(), value!("ann")),
+            ("limit".to_string(), value!(3)),
+        ]);
+
+        evaluator
+            .eval_expr_with(&opts, app(), "output.value", bindings)
+            .unwrap();
+
+        let sent = mock
+            .sent_messages::<SentCreate>(CreateEvaluatorRequest::CODE)
+            .unwrap();
+        let allowed = sent[0].allowed_resources.clone().unwrap_or_default();
+        assert!(allows(&allowed, "prop:user.name"));
+        assert!(allows(&allowed, "prop:limit"));
+        assert!(!allows(&allowed, "prop:secret"));
+        assert!(!allows(&allowed, "prop:userXname"));
+        assert!(!allows(&allowed, "prop:limits"));
+
+        let properties = sent[0].properties.clone().unwrap_or_default();
+        assert_eq!(properties["user.name"], "ann");
+        assert_eq!(properties["limit"], "3");
+    }
+
+    #[test]
+    fn evaluator_is_closed_after_eval() {
+        let (proto, mock) = MockProtocol::new(CREATE_EVALUATE);
+        let mut evaluator = Evaluator::new(proto);
+
+        evaluator.eval(&EvalOpts::default(), app()).unwrap();
+
+        assert_eq!(mock.sent_codes().unwrap(), [0x20, 0x23, 0x22]);
+        assert_eq!(closed_evaluators(&mock), [1]);
+    }
+
+    #[test]
+    fn evaluator_is_closed_when_pkl_fails() {
+        let (proto, mock) = MockProtocol::new(evaluation(0, 1, "error", value!("boom")));
+        let mut evaluator = Evaluator::new(proto);
+
+        let err = evaluator.eval(&EvalOpts::default(), app()).unwrap_err();
+
+        assert!(matches!(err, Error::Pkl(_)));
+        assert_eq!(closed_evaluators(&mock), [1]);
+    }
+
+    #[test]
+    fn evaluator_is_closed_when_decoding_fails() {
+        let result = Value::Bytes(vec![0xc1]);
+        let (proto, mock) = MockProtocol::new(evaluation(0, 1, "result", result));
+        let mut evaluator = Evaluator::new(proto);
+
+        let err = evaluator.eval(&EvalOpts::default(), app()).unwrap_err();
+
+        assert!(matches!(err, Error::Value(_)));
+        assert_eq!(closed_evaluators(&mock), [1]);
+    }
+
+    struct Vault;
+
+    impl ResourceReader for Vault {
+        fn scheme(&self) -> &str {
+            "vault"
+        }
+
+        fn read(&self, _uri: &str) -> Result<Vec<u8>, String> {
+            Ok(b"secret".to_vec())
+        }
+    }
+
+    fn with_vault(allowed_resources: &[&str]) -> EvalOpts {
+        EvalOpts {
+            allowed_resources: allowed_resources.iter().map(|p| p.to_string()).collect(),
+            client_resource_readers: vec![Arc::new(Vault)],
+            ..EvalOpts::default()
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn reader_schemes_are_matched_as_regex() {
+        for allowed in [
+            "vault:",
+            "^vault:",
+            "vault:.*",
+            "^vault://prod/",
+            "(vault|consul):",
+        ] {
+            assert!(
+                with_vault(&[allowed]).validate_readers().is_ok(),
+                "{allowed}"
+            );
+        }
+
+        let err = with_vault(&["https:", "^vaults:"])
+            .validate_readers()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ReaderNotAllowed { kind: "resource", scheme } if scheme == "vault"
+        ));
+    }
+
+    #[cfg(not(feature = "regex"))]
+    #[test]
+    fn reader_schemes_are_matched_by_prefix() {
+        for allowed in ["vault:", "^vault:", "vault:.*", "^vault://prod/"] {
+            assert!(
+                with_vault(&[allowed]).validate_readers().is_ok(),
+                "{allowed}"
+            );
+        }
+
+        let err = with_vault(&["(vault|consul):"])
+            .validate_readers()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ReaderNotAllowed { kind: "resource", scheme } if scheme == "vault"
+        ));
+    }
+
+    #[test]
+    fn allowed_schemes_need_a_reader() {
+        let opts = EvalOpts::default()
+            .allowing_resource("env:")
+            .allowing_resource("^https:")
+            .allowing_module("file:.*");
+        assert!(opts.validate_readers().is_ok());
+
+        let err = opts
+            .allowing_resource("^vault:")
+            .validate_readers()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ReaderMissing { kind: "resource", scheme } if scheme == "vault"
+        ));
+
+        let err = opts.allowing_module("gen:").validate_readers().unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ReaderMissing { kind: "module", scheme } if scheme == "gen"
+        ));
+    }
+
+    #[test]
+    fn inline_resources_serve_their_scheme() {
+        let mut opts = EvalOpts::default().allowing_resource("config:");
+        opts.inline_resources
+            .insert("config://db".to_string(), b"postgres".to_vec());
+
+        assert!(opts.validate_readers().is_ok());
+    }
+
+    /// Whether pkl would allow `uri`: some pattern matches at its start.
+    fn allows(patterns: &[String], uri: &str) -> bool {
+        patterns.iter().any(|pattern| {
+            Regex::new(&format!("^(?:{pattern})"))
+                .unwrap()
+                .is_match(uri)
+        })
+    }
+
+    #[test]
+    fn file_root_confines_file_access() {
+        let root = std::env::temp_dir().join(format!("rust-pkl-file-root-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let mut opts = EvalOpts::default()
+            .allowing_module("file:")
+            .allowing_resource("file:");
+
+        let confined = opts.file_root(&root);
+        let root = fs::canonicalize(&root).unwrap();
+        fs::remove_dir(&root).unwrap();
+        confined.unwrap();
+
+        let inside = Uri::File(root.join("app.pkl")).to_string();
+        let sibling = Uri::File(root.with_extension("evil").join("app.pkl")).to_string();
+
+        for patterns in [&opts.allowed_modules, &opts.allowed_resources] {
+            assert!(allows(patterns, &inside));
+            assert!(!allows(patterns, "file:///etc/passwd"));
+            assert!(!allows(patterns, &sibling));
+        }
+
+        let (proto, mock) = MockProtocol::new(CREATE_EVALUATE);
+        Evaluator::new(proto)
+            .eval(&opts, Uri::File(root.join("app.pkl")))
+            .unwrap();
+
+        let sent = mock
+            .sent_messages::<SentCreate>(CreateEvaluatorRequest::CODE)
+            .unwrap();
+        assert_eq!(sent[0].root_dir.as_deref(), root.to_str());
+
+        let allowed_modules = sent[0].allowed_modules.as_deref().unwrap();
+        assert!(allows(allowed_modules, &inside));
+        assert!(!allows(allowed_modules, "file:///etc/passwd"));
+    }
+
+    fn sent_output_format(output_format: Option<OutputFormat>) -> Option<String> {
+        let (proto, mock) = MockProtocol::new(CREATE_EVALUATE);
+        let opts = EvalOpts {
+            output_format,
+            ..EvalOpts::default()
+        };
+
+        Evaluator::new(proto).eval(&opts, app()).unwrap();
+
+        let keys = mock
+            .sent_messages::<HashMap<String, IgnoredAny>>(CreateEvaluatorRequest::CODE)
+            .unwrap();
+        let mut sent = mock
+            .sent_messages::<SentCreate>(CreateEvaluatorRequest::CODE)
+            .unwrap();
+        assert_eq!(
+            keys[0].contains_key("outputFormat"),
+            sent[0].output_format.is_some()
+        );
+
+        sent.remove(0).output_format
+    }
+
+    #[test]
+    fn output_format_is_left_out_when_unset() {
+        assert_eq!(sent_output_format(None), None);
+        assert_eq!(
+            sent_output_format(Some(OutputFormat::Yaml)).as_deref(),
+            Some("yaml")
+        );
+        assert_eq!(
+            sent_output_format(Some(OutputFormat::Pkl)).as_deref(),
+            Some("pcf")
+        );
+    }
+
+    fn project() -> Project {
+        let lib = Project {
+            project_file_uri: Uri::File("/deps/lib/PklProject".into()),
+            ..Project::default()
+        };
+
+        Project {
+            project_file_uri: Uri::File("/app/PklProject".into()),
+            dependencies: HashMap::from([("lib".to_string(), ProjectDependency::Local(lib))]),
+            ..Project::default()
+        }
+    }
+
+    fn allowed_modules(project: Option<Project>, explicit: &[&str]) -> Vec<String> {
+        let opts = EvalOpts {
+            allowed_modules: explicit.iter().map(|p| p.to_string()).collect(),
+            project,
+            ..EvalOpts::default()
+        };
+
+        opts.effective_allowed_modules(Some(&app()))
+    }
+
+    #[test]
+    fn allowed_modules_without_project_or_explicit_patterns() {
+        assert_eq!(allowed_modules(None, &[]), ["file:"]);
+    }
+
+    #[test]
+    fn allowed_modules_with_explicit_patterns_only() {
+        assert_eq!(
+            allowed_modules(None, &["pkl:", "https:"]),
+            ["pkl:", "https:", "file:"]
+        );
+        assert_eq!(allowed_modules(None, &["pkl:", "file:"]), ["pkl:", "file:"]);
+    }
+
+    #[test]
+    fn allowed_modules_with_project_only() {
+        assert_eq!(
+            allowed_modules(Some(project()), &[]),
+            ["projectpackage:", "file:///deps/lib/", "file:"]
+        );
+    }
+
+    #[test]
+    fn allowed_modules_with_project_and_explicit_patterns() {
+        assert_eq!(
+            allowed_modules(Some(project()), &["pkl:", "^file:///app/"]),
+            [
+                "pkl:",
+                "^file:///app/",
+                "projectpackage:",
+                "file:///deps/lib/"
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_evaluations_leave_no_evaluator_behind() {
+        let mut transcript = evaluation(0, 1, "error", value!("boom"));
+        transcript.extend(evaluation(2, 2, "error", value!("boom")));
+        transcript.extend(evaluation(4, 3, "result", Value::Bytes(b"\xa2ok".to_vec())));
+        let (proto, mock) = MockProtocol::new(transcript);
+        let mut evaluator = Evaluator::new(proto);
+        let opts = EvalOpts::default();
+
+        assert!(evaluator.eval(&opts, app()).is_err());
+        assert!(
+            evaluator
+                .eval_into_buf(&opts, app(), &mut Vec::new())
+                .is_err()
+        );
+        assert_eq!(evaluator.eval(&opts, app()).unwrap(), Some(value!("ok")));
+
+        assert_eq!(closed_evaluators(&mock), [1, 2, 3]);
     }
 }
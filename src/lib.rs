@@ -1,6 +1,58 @@
+use std::{fs, path::Path};
+
+use serde::de::DeserializeOwned;
+
+#[cfg(feature = "tokio")]
+pub use crate::{
+    async_evaluator::AsyncEvaluator, async_protocol::AsyncProtocol, pool::EvaluatorPool,
+};
+pub use crate::{
+    client::{Project, Uri},
+    errors::{Error, PklError, ValueError},
+    evaluator::{EvalOpts, Evaluator, OutputFormat},
+    protocol::Protocol,
+    reader::{ModuleReader, ResourceReader},
+    server::{Object, Value},
+    session::Session,
+};
+
+#[cfg(feature = "tokio")]
+pub mod async_evaluator;
+#[cfg(feature = "tokio")]
+pub mod async_protocol;
+pub mod cache;
 pub mod client;
+mod de;
 pub mod decoder;
 pub mod errors;
 pub mod evaluator;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+pub mod observer;
+mod pipe;
+#[cfg(feature = "tokio")]
+pub mod pool;
 pub mod protocol;
+pub mod reader;
+pub mod reflect;
 pub mod server;
+pub mod session;
+
+/// Evaluates `output.value` of the Pkl file at `path` using a throwaway `pkl server`
+/// process and default options (plus access to `file:` modules).
+pub fn eval_file(path: impl AsRef<Path>) -> Result<Value, Error> {
+    let path = fs::canonicalize(path)?;
+    let opts = EvalOpts::default().allowing_module("file:");
+
+    let mut evaluator = Evaluator::new(Protocol::new()?);
+    let result = evaluator.eval_expr(&opts, Uri::File(path), "output.value");
+    evaluator.close()?;
+
+    result?.ok_or(Error::EmptyResult)
+}
+
+/// Like [`eval_file`], but deserializes the result into `T`, like
+/// [`Evaluator::eval_into`].
+pub fn eval_file_into<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T, Error> {
+    Ok(T::deserialize(&eval_file(path)?)?)
+}
@@ -0,0 +1,171 @@
+//! Replays recorded `pkl server` output so the protocol and decoder can be exercised
+//! without a pkl binary. Only available with the `test-util` feature, and to the
+//! crate's own tests.
+
+use std::{
+    io::{self, Cursor, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use rmp::Marker;
+use serde::{
+    Serialize,
+    de::{DeserializeOwned, IgnoredAny},
+};
+
+use crate::{
+    errors::Error,
+    protocol::{self, Protocol},
+};
+
+/// Server output of a `CreateEvaluator` followed by an `Evaluate` of a module
+/// `file:///app.pkl` with `name = "demo"` and `port = 8080`, answering request ids
+/// 0 and 1 for evaluator 1.
+pub const CREATE_EVALUATE: &[u8] = include_bytes!("../fixtures/create_evaluate.msgpack");
+
+/// Same as [`CREATE_EVALUATE`], with a trace and a warn `Log` message sent before
+/// the evaluate response.
+pub const EVALUATE_WITH_LOGS: &[u8] = include_bytes!("../fixtures/evaluate_with_logs.msgpack");
+
+/// Frames `body` as a message with `code`, for building transcripts of server
+/// responses the recorded fixtures don't cover.
+pub fn message(code: u64, body: impl Serialize) -> Result<Vec<u8>, Error> {
+    protocol::encode_raw(code, body)
+}
+
+/// Handle to a [`Protocol`] that reads a recorded transcript instead of talking to a
+/// `pkl server` process, and captures everything the client writes.
+///
+/// Once the transcript is exhausted, reads hit end of file, so a test that expects
+/// more messages than were recorded fails with an I/O error rather than hanging.
+pub struct MockProtocol {
+    sent: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MockProtocol {
+    /// Creates a protocol replaying `transcript`, a sequence of framed server
+    /// messages, and the handle used to inspect what the client sent to it.
+    pub fn new(transcript: impl Into<Vec<u8>>) -> (Protocol, MockProtocol) {
+        Self::from_reader(Cursor::new(transcript.into()))
+    }
+
+    /// Like [`MockProtocol::new`], but reads the server output from `stdout`, e.g. the
+    /// reading end of an [`std::io::pipe`] for tests that write server messages while
+    /// the client waits for them.
+    pub fn from_reader(stdout: impl Read + Send + 'static) -> (Protocol, MockProtocol) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let protocol = Protocol::from_pipes(SharedWriter(Arc::clone(&sent)), stdout);
+
+        (protocol, MockProtocol { sent })
+    }
+
+    /// Raw bytes the client has sent so far.
+    pub fn sent(&self) -> Vec<u8> {
+        self.sent
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Message codes the client has sent so far, in order.
+    pub fn sent_codes(&self) -> Result<Vec<u64>, Error> {
+        let mut codes = Vec::new();
+
+        self.read_sent(|code, body| {
+            rmp_serde::from_read::<_, IgnoredAny>(body)?;
+            codes.push(code);
+            Ok(())
+        })?;
+
+        Ok(codes)
+    }
+
+    /// Bodies of the messages with `code` the client has sent so far, in order.
+    /// `T` only needs the fields a test looks at, named as on the wire.
+    pub fn sent_messages<T: DeserializeOwned>(&self, code: u64) -> Result<Vec<T>, Error> {
+        let mut messages = Vec::new();
+
+        self.read_sent(|sent_code, body| {
+            if sent_code == code {
+                messages.push(rmp_serde::from_read(body)?);
+            } else {
+                rmp_serde::from_read::<_, IgnoredAny>(body)?;
+            }
+            Ok(())
+        })?;
+
+        Ok(messages)
+    }
+
+    /// Calls `f` with the code of every sent message and a reader positioned at its
+    /// body, which `f` must consume.
+    fn read_sent(
+        &self,
+        mut f: impl FnMut(u64, &mut Cursor<&[u8]>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let sent = self.sent();
+        let mut reader = Cursor::new(sent.as_slice());
+
+        while (reader.position() as usize) < sent.len() {
+            let marker = rmp::decode::read_marker(&mut reader)?;
+
+            if !matches!(marker, Marker::FixArray(2)) {
+                return Err(Error::InvalidMarker(marker));
+            }
+
+            let code = rmp_serde::from_read(&mut reader)?;
+            f(code, &mut reader)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Response;
+
+    /// Reads every message of `transcript`, describing each by its kind and, for
+    /// logs, level.
+    fn replay(transcript: &[u8]) -> Vec<String> {
+        let (mut proto, _mock) = MockProtocol::new(transcript);
+        let mut messages = Vec::new();
+
+        while let Ok(response) = proto.recv_any() {
+            messages.push(match response {
+                Response::CreateEvaluator(response) => format!("create {}", response.request_id),
+                Response::Evaluate(response) => format!("evaluate {}", response.request_id),
+                Response::Log(log) => format!("log {}", log.level),
+                _ => "other".to_string(),
+            });
+        }
+
+        messages
+    }
+
+    #[test]
+    fn fixtures_hold_the_documented_messages() {
+        assert_eq!(replay(CREATE_EVALUATE), ["create 0", "evaluate 1"]);
+        assert_eq!(
+            replay(EVALUATE_WITH_LOGS),
+            ["create 0", "log 0", "log 1", "evaluate 1"]
+        );
+    }
+}
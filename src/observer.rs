@@ -0,0 +1,76 @@
+use crate::server::{
+    CloseExternalProcess, CreateEvaluatorResponse, EvaluateResponse, InitializeModuleReaderRequest,
+    InitializeResourceReaderRequest, ListModulesRequest, ListResourcesRequest, Log,
+    ReadModuleRequest, ReadResourceRequest, Response,
+};
+
+/// Severity of a [`LogEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    /// Output of `trace()` in Pkl code.
+    Trace,
+    /// Warnings, e.g. about deprecated members.
+    Warn,
+}
+
+impl From<i64> for LogLevel {
+    /// pkl sends 0 for trace and 1 for warn; anything else is treated as a warning.
+    fn from(level: i64) -> Self {
+        match level {
+            0 => LogLevel::Trace,
+            _ => LogLevel::Warn,
+        }
+    }
+}
+
+/// A `Log` message from the server, as passed to [`crate::Evaluator::on_log`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEvent {
+    pub level: LogLevel,
+    pub message: String,
+    /// Module location that produced the message.
+    pub frame_uri: String,
+}
+
+impl From<Log> for LogEvent {
+    fn from(log: Log) -> Self {
+        LogEvent {
+            level: log.level.into(),
+            message: log.message,
+            frame_uri: log.frame_uri,
+        }
+    }
+}
+
+/// Hooks invoked for every message received from the pkl server, before the crate
+/// handles it. All methods default to doing nothing, so implementors only override
+/// the messages they care about.
+pub trait MessageObserver: Send {
+    fn on_create_evaluator(&mut self, _response: &CreateEvaluatorResponse) {}
+    fn on_evaluate(&mut self, _response: &EvaluateResponse) {}
+    fn on_log(&mut self, _log: &Log) {}
+    fn on_read_resource(&mut self, _request: &ReadResourceRequest) {}
+    fn on_read_module(&mut self, _request: &ReadModuleRequest) {}
+    fn on_list_resources(&mut self, _request: &ListResourcesRequest) {}
+    fn on_list_modules(&mut self, _request: &ListModulesRequest) {}
+    fn on_initialize_module_reader(&mut self, _request: &InitializeModuleReaderRequest) {}
+    fn on_initialize_resource_reader(&mut self, _request: &InitializeResourceReaderRequest) {}
+    fn on_close_external_process(&mut self, _request: &CloseExternalProcess) {}
+}
+
+pub(crate) fn notify(observer: &mut dyn MessageObserver, response: &Response) {
+    match response {
+        Response::CreateEvaluator(response) => observer.on_create_evaluator(response),
+        Response::Evaluate(response) => observer.on_evaluate(response),
+        Response::Log(log) => observer.on_log(log),
+        Response::ReadResource(request) => observer.on_read_resource(request),
+        Response::ReadModule(request) => observer.on_read_module(request),
+        Response::ListResources(request) => observer.on_list_resources(request),
+        Response::ListModules(request) => observer.on_list_modules(request),
+        Response::InitializeModuleReader(request) => observer.on_initialize_module_reader(request),
+        Response::InitializeResourceReader(request) => {
+            observer.on_initialize_resource_reader(request)
+        }
+        Response::CloseExternalProcess(request) => observer.on_close_external_process(request),
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    io::{self, Read},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+    },
+    thread,
+    time::Duration,
+};
+
+/// Default size of the buffer each read from the pipe goes into.
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Reads a pipe on a background thread so callers can wait for data with a timeout,
+/// which blocking reads on the pipe itself can't do.
+pub(crate) struct PipeReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk_size: Arc<AtomicUsize>,
+    chunk: Vec<u8>,
+    pos: usize,
+    error: Option<io::Error>,
+}
+
+impl PipeReader {
+    pub(crate) fn spawn(mut pipe: impl Read + Send + 'static) -> Self {
+        let (tx, chunks) = mpsc::channel();
+        let chunk_size = Arc::new(AtomicUsize::new(DEFAULT_CHUNK_SIZE));
+        let size = Arc::clone(&chunk_size);
+
+        thread::spawn(move || {
+            loop {
+                let mut chunk = vec![0; size.load(Ordering::Relaxed)];
+
+                let message = match pipe.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        Ok(chunk)
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+
+                let failed = message.is_err();
+
+                if tx.send(message).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            chunks,
+            chunk_size,
+            chunk: Vec::new(),
+            pos: 0,
+            error: None,
+        }
+    }
+
+    /// Sets the size of the buffer for reads from the pipe. A read that is already
+    /// waiting for data keeps its buffer, so this applies from the next one on.
+    pub(crate) fn set_chunk_size(&self, size: usize) {
+        self.chunk_size.store(size.max(1), Ordering::Relaxed);
+    }
+
+    /// Waits up to `timeout` for something to read. Returns `true` when a read won't
+    /// block, which includes end of file and errors.
+    pub(crate) fn wait(&mut self, timeout: Duration) -> bool {
+        if self.pos < self.chunk.len() || self.error.is_some() {
+            return true;
+        }
+
+        match self.chunks.recv_timeout(timeout) {
+            Ok(Ok(chunk)) => {
+                self.chunk = chunk;
+                self.pos = 0;
+                true
+            }
+            Ok(Err(err)) => {
+                self.error = Some(err);
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        }
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        if self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
@@ -0,0 +1,242 @@
+use std::sync::Mutex;
+
+use tokio::sync::Semaphore;
+use tracing::{debug, instrument};
+
+use crate::{
+    async_evaluator::AsyncEvaluator, client::Uri, errors::Error, evaluator::EvalOpts,
+    protocol::ProtocolBuilder, server::Value,
+};
+
+/// Spreads evaluations over up to `size` `pkl server` processes, so concurrent
+/// callers don't queue behind a single process.
+///
+/// Processes are spawned on first use and reused afterwards. When all of them are
+/// busy, [`EvaluatorPool::eval`] waits for one to become idle. A process that has
+/// exited, or that failed in a way that leaves its pipes in an unknown state, is
+/// dropped and replaced by a fresh one on the next evaluation.
+pub struct EvaluatorPool {
+    builder: ProtocolBuilder,
+    idle: Mutex<Vec<AsyncEvaluator>>,
+    slots: Semaphore,
+}
+
+impl EvaluatorPool {
+    /// A pool of up to `size` processes spawned like [`crate::AsyncProtocol::new`].
+    /// A `size` of zero is treated as one.
+    pub fn new(size: usize) -> Self {
+        Self::with_builder(size, ProtocolBuilder::default())
+    }
+
+    /// Like [`EvaluatorPool::new`], but spawns each process from `builder`.
+    pub fn with_builder(size: usize, builder: ProtocolBuilder) -> Self {
+        Self {
+            builder,
+            idle: Mutex::new(Vec::new()),
+            slots: Semaphore::new(size.max(1)),
+        }
+    }
+
+    /// Evaluates the module at `uri` on an idle process, like
+    /// [`Evaluator::eval`](crate::Evaluator::eval). Must be called from within a
+    /// tokio runtime.
+    ///
+    /// Cancelling the returned future kills the process it was using.
+    #[instrument(skip(self, opts))]
+    pub async fn eval(&self, opts: &EvalOpts, uri: Uri) -> Result<Option<Value>, Error> {
+        let _slot = self
+            .slots
+            .acquire()
+            .await
+            .expect("the pool never closes its semaphore");
+        let mut evaluator = self.checkout()?;
+
+        let result = evaluator.eval(opts, uri).await;
+
+        match &result {
+            Err(err) if !keeps_process(err) || !evaluator.is_usable() => {
+                debug!(?err, "dropping pkl server")
+            }
+            _ => self.idle().push(evaluator),
+        }
+
+        result
+    }
+
+    /// An idle evaluator whose process is still usable, or a new one.
+    fn checkout(&self) -> Result<AsyncEvaluator, Error> {
+        while let Some(mut evaluator) = self.idle().pop() {
+            if evaluator.is_usable() {
+                return Ok(evaluator);
+            }
+
+            debug!("replacing unusable pkl server");
+        }
+
+        Ok(AsyncEvaluator::new(self.builder.clone().spawn_async()?))
+    }
+
+    fn idle(&self) -> std::sync::MutexGuard<'_, Vec<AsyncEvaluator>> {
+        self.idle.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Whether the process that produced `err` can still be used: pkl answered, but
+/// with an error or a result this crate rejected. A `Value` error can also mean the
+/// process's output is unreadable, which [`AsyncEvaluator::is_usable`] catches.
+fn keeps_process(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Pkl(_)
+            | Error::Value(_)
+            | Error::ResultTooLarge { .. }
+            | Error::ReaderNotAllowed { .. }
+            | Error::InvalidOutputFormat(_)
+            | Error::InvalidAllowPattern(_)
+            | Error::ProjectNotFound(_)
+    )
+}
+
+// `pkl server` is played by `sh`: each process appends a line to a counter file,
+// prints a transcript and then waits for stdin to close.
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, path::PathBuf, thread, time::Duration};
+
+    use super::*;
+    use crate::{
+        mock,
+        protocol::{Message, Protocol},
+        server::{CreateEvaluatorResponse, EvaluateResponse},
+        value,
+    };
+
+    /// Server output for `evals` evaluations in a row on one evaluator process.
+    fn transcript(evals: u64) -> Vec<u8> {
+        let mut transcript = Vec::new();
+
+        for eval in 0..evals {
+            let created = value!({ "requestId" => (2 * eval), "evaluatorId" => 1 });
+            let result = Value::Bytes(vec![0x01]);
+            let evaluated = value!({
+                "requestId" => (2 * eval + 1),
+                "evaluatorId" => 1,
+                "result" => result,
+            });
+
+            transcript.extend(mock::message(CreateEvaluatorResponse::CODE, created).unwrap());
+            transcript.extend(mock::message(EvaluateResponse::CODE, evaluated).unwrap());
+        }
+
+        transcript
+    }
+
+    /// Files for a fake server in a fresh directory named after `test`.
+    struct Server {
+        dir: PathBuf,
+    }
+
+    impl Server {
+        /// A server whose first process prints `first` and then either waits for stdin
+        /// to close or, if `then` is `exit`, exits shortly after. Later processes print
+        /// `rest` and wait.
+        fn new(test: &str, first: &[u8], then: &str, rest: &[u8]) -> (Self, EvaluatorPool) {
+            let dir =
+                std::env::temp_dir().join(format!("rust-pkl-pool-{test}-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("first"), first).unwrap();
+            fs::write(dir.join("rest"), rest).unwrap();
+
+            let script = format!(
+                r#"cd "{}"; echo >>spawned
+                if [ "$(wc -l <spawned)" -eq 1 ]; then cat first; [ {then} = exit ] && {{ sleep 0.1; exit; }}; else cat rest; fi
+                exec cat >/dev/null"#,
+                dir.display()
+            );
+            let builder = Protocol::builder().command("sh", ["-c", &script]);
+
+            (Server { dir }, EvaluatorPool::with_builder(1, builder))
+        }
+
+        fn spawned(&self) -> usize {
+            fs::read_to_string(self.dir.join("spawned"))
+                .unwrap()
+                .lines()
+                .count()
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_processes_are_reused() {
+        let (server, pool) = Server::new("reuse", &transcript(2), "wait", &[]);
+
+        assert_eq!(
+            pool.eval(&EvalOpts::default(), Uri::default())
+                .await
+                .unwrap(),
+            Some(value!(1))
+        );
+        assert_eq!(
+            pool.eval(&EvalOpts::default(), Uri::default())
+                .await
+                .unwrap(),
+            Some(value!(1))
+        );
+        assert_eq!(server.spawned(), 1);
+    }
+
+    #[tokio::test]
+    async fn exited_processes_are_replaced() {
+        let (server, pool) = Server::new("exited", &transcript(1), "exit", &transcript(1));
+
+        pool.eval(&EvalOpts::default(), Uri::default())
+            .await
+            .unwrap();
+        // Let the first process exit before it is checked out again.
+        thread::sleep(Duration::from_millis(300));
+        pool.eval(&EvalOpts::default(), Uri::default())
+            .await
+            .unwrap();
+
+        assert_eq!(server.spawned(), 2);
+    }
+
+    #[tokio::test]
+    async fn processes_are_dropped_after_an_io_error() {
+        let (server, pool) = Server::new("io-error", &[], "exit", &transcript(1));
+
+        let err = pool
+            .eval(&EvalOpts::default(), Uri::default())
+            .await
+            .unwrap_err();
+        pool.eval(&EvalOpts::default(), Uri::default())
+            .await
+            .unwrap();
+
+        assert!(matches!(err, Error::IO(_)), "{err:?}");
+        assert_eq!(server.spawned(), 2);
+    }
+
+    #[tokio::test]
+    async fn processes_with_unreadable_output_are_dropped() {
+        // 0xc1 is a reserved marker, so no message can be read past it.
+        let (server, pool) = Server::new("unreadable", b"\xc1", "wait", &transcript(1));
+
+        let err = pool
+            .eval(&EvalOpts::default(), Uri::default())
+            .await
+            .unwrap_err();
+        pool.eval(&EvalOpts::default(), Uri::default())
+            .await
+            .unwrap();
+
+        assert!(matches!(err, Error::Value(_)), "{err:?}");
+        assert_eq!(server.spawned(), 2);
+    }
+}
@@ -1,45 +1,389 @@
 use std::{
-    io::Write,
-    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    collections::{HashMap, HashSet},
+    env,
+    ffi::{OsStr, OsString},
+    io::{self, Read, Write},
+    mem,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
 use rmp_serde::{Serializer, config::BytesMode};
 use serde::{Serialize, de::DeserializeOwned};
-use tracing::instrument;
+use tracing::{debug, instrument, trace, warn};
 
+#[cfg(feature = "tokio")]
+use crate::async_protocol::AsyncProtocol;
 use crate::{
-    client::{CreateEvaluatorRequest, EvaluateRequest},
+    client::{
+        CloseEvaluator, CreateEvaluatorRequest, EvaluateRequest, InitializeModuleReaderResponse,
+        InitializeResourceReaderResponse, ListModulesResponse, ListResourcesResponse,
+        ReadModuleResponse, ReadResourceResponse, Uri,
+    },
     decoder::Decoder,
     errors::Error,
-    server::{CreateEvaluatorResponse, EvaluateResponse, Response},
+    observer::{self, LogEvent, LogLevel, MessageObserver},
+    pipe::PipeReader,
+    reader::{self, MAX_RESOURCE_LEN, ModuleReader, ResourceReader},
+    server::{CloseExternalProcess, CreateEvaluatorResponse, EvaluateResponse, Response},
 };
 
+/// How long [`Protocol::shutdown`] waits for the server to exit before killing it.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// Request id used by [`Protocol::ping`]. [`crate::Evaluator`] counts up from zero,
+/// so it never hands this one out.
+pub const PING_REQUEST_ID: u64 = u64::MAX;
+
+/// What to do with a response whose request id doesn't belong to any request in
+/// flight. Answers to other requests in flight are kept until they are waited for,
+/// so such a response repeats one that was already delivered, e.g. from a server
+/// echoing a request id twice.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateResponsePolicy {
+    /// Fail with `Error::DuplicateResponse`.
+    #[default]
+    Error,
+    /// Log a warning, drop the response and keep waiting.
+    Ignore,
+}
+
+impl DuplicateResponsePolicy {
+    /// Handles a duplicate answer to `request_id` according to the policy.
+    pub(crate) fn apply(self, request_id: u64) -> Result<(), Error> {
+        match self {
+            DuplicateResponsePolicy::Error => Err(Error::DuplicateResponse { request_id }),
+            DuplicateResponsePolicy::Ignore => {
+                warn!(request_id, "ignoring duplicate response");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Program started for `pkl server`. On Windows, `Command` only looks for `.exe`
+/// files on `PATH` when given a bare name, while pkl is commonly installed as a
+/// `pkl.bat` launcher, so each extension is tried in turn, `.exe` first to match what
+/// a bare `pkl` would find.
+fn pkl_program() -> OsString {
+    #[cfg(windows)]
+    if let Some(path) = env::var_os("PATH") {
+        for dir in env::split_paths(&path) {
+            for name in ["pkl.exe", "pkl.bat", "pkl.cmd"] {
+                let candidate = dir.join(name);
+
+                if candidate.is_file() {
+                    return candidate.into_os_string();
+                }
+            }
+        }
+    }
+
+    OsString::from("pkl")
+}
+
 pub trait Message {
     const CODE: u64;
 }
 
 pub struct Protocol {
-    child: Child,
-    stdin: ChildStdin,
-    stdout: ChildStdout,
+    /// `None` when the protocol runs over in-memory pipes, see `mock::MockProtocol`.
+    child: Option<Child>,
+    stdin: Box<dyn Write + Send>,
+    stdout: PipeReader,
+    observer: Option<Box<dyn MessageObserver>>,
+    requests: ServerRequests,
+    pending: PendingResponses,
+    duplicate_policy: DuplicateResponsePolicy,
+    read_timeout: Option<Duration>,
 }
 
-impl Protocol {
-    pub fn new() -> Result<Self, Error> {
-        let mut child = Command::new("pkl")
+/// Matches responses to the requests in flight by request id, so an answer that
+/// arrives while another request is being waited for is kept for its own waiter
+/// instead of being mistaken for the awaited one.
+#[derive(Default)]
+pub(crate) struct PendingResponses {
+    in_flight: HashSet<u64>,
+    buffered: HashMap<u64, Response>,
+    /// Number of answers per request id that were given up on and are dropped when
+    /// they arrive late.
+    abandoned: HashMap<u64, usize>,
+}
+
+/// Answers the requests pkl sends back while evaluating: reads and listings for the
+/// registered readers, reader initialization and logs. Shared by [`Protocol`] and
+/// the async protocol, which only differ in how the answers are written.
+#[derive(Default)]
+pub(crate) struct ServerRequests {
+    resource_readers: HashMap<i64, Vec<Arc<dyn ResourceReader>>>,
+    module_readers: HashMap<i64, Vec<Arc<dyn ModuleReader>>>,
+    log_handler: Option<Box<dyn FnMut(LogEvent) + Send>>,
+}
+
+#[derive(Default, Clone)]
+pub struct ProtocolBuilder {
+    current_dir: Option<PathBuf>,
+    temp_dir: Option<PathBuf>,
+    wrapper: Option<(OsString, Vec<OsString>)>,
+    command: Option<(OsString, Vec<OsString>)>,
+}
+
+impl ProtocolBuilder {
+    /// Working directory of the `pkl server` process. Relative module paths and
+    /// `PklProject` discovery resolve against it for the lifetime of the process.
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Directory for pkl's temporary files, for environments where the default
+    /// (usually `/tmp`) isn't writable such as containers with a read-only root.
+    ///
+    /// Sets `TMPDIR`, `TMP` and `TEMP` for the child. The JVM distribution of pkl
+    /// reads `java.io.tmpdir` instead, so it additionally gets
+    /// `-Djava.io.tmpdir` through `JAVA_TOOL_OPTIONS`.
+    pub fn temp_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = Some(dir.into());
+        self
+    }
+
+    /// Launches pkl through another program, e.g. `nix run` or `sudo -u`, so the
+    /// command becomes `<program> <args...> pkl server`.
+    pub fn wrapper<I, S>(mut self, program: impl AsRef<OsStr>, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let args = args
+            .into_iter()
+            .map(|arg| arg.as_ref().to_owned())
+            .collect();
+        self.wrapper = Some((program.as_ref().to_owned(), args));
+        self
+    }
+
+    /// Runs pkl from `program` instead of looking it up, passing `args` before the
+    /// `server` subcommand, e.g. `/opt/pkl/bin/pkl` or a `java` binary with `-Xmx2g
+    /// -jar pkl.jar`.
+    ///
+    /// Without this, the `PKL_EXEC` environment variable names the binary when set,
+    /// falling back to `pkl` on `PATH`.
+    pub fn command<I, S>(mut self, program: impl AsRef<OsStr>, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let args = args
+            .into_iter()
+            .map(|arg| arg.as_ref().to_owned())
+            .collect();
+        self.command = Some((program.as_ref().to_owned(), args));
+        self
+    }
+
+    pub fn spawn(self) -> Result<Protocol, Error> {
+        let mut command = self.server_command();
+        let mut child = command
+            .spawn()
+            .map_err(|source| spawn_error(&command, source))?;
+        let stdin = child.stdin.take().ok_or(Error::Pipe)?;
+        let stdout = child.stdout.take().ok_or(Error::Pipe)?;
+
+        let mut protocol = Protocol::from_pipes(stdin, stdout);
+        protocol.child = Some(child);
+
+        Ok(protocol)
+    }
+
+    /// Like [`ProtocolBuilder::spawn`], but starts the process on tokio for an
+    /// [`AsyncProtocol`]. Must be called from within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_async(self) -> Result<AsyncProtocol, Error> {
+        let mut command = tokio::process::Command::from(self.server_command());
+        command.kill_on_drop(true);
+
+        let child = command
+            .spawn()
+            .map_err(|source| spawn_error(command.as_std(), source))?;
+
+        AsyncProtocol::from_child(child)
+    }
+
+    /// The `pkl server` command with pipes for stdin and stdout.
+    fn server_command(&self) -> Command {
+        let (program, program_args) = match self.command.clone() {
+            Some(command) => command,
+            None => (
+                env::var_os("PKL_EXEC").unwrap_or_else(pkl_program),
+                Vec::new(),
+            ),
+        };
+
+        let mut command = match &self.wrapper {
+            Some((wrapper, args)) => {
+                let mut command = Command::new(wrapper);
+                command.args(args).arg(&program);
+                command
+            }
+            None => Command::new(&program),
+        };
+        command
+            .args(&program_args)
             .arg("server")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .spawn()?;
-        let stdin = child.stdin.take().ok_or(Error::Pipe)?;
-        let stdout = child.stdout.take().ok_or(Error::Pipe)?;
+            .stderr(Stdio::inherit());
+
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+
+        if let Some(dir) = &self.temp_dir {
+            let mut tool_options = OsString::from("-Djava.io.tmpdir=");
+            tool_options.push(dir);
+
+            if let Some(existing) = env::var_os("JAVA_TOOL_OPTIONS") {
+                tool_options.push(" ");
+                tool_options.push(existing);
+            }
+
+            command
+                .env("TMPDIR", dir)
+                .env("TMP", dir)
+                .env("TEMP", dir)
+                .env("JAVA_TOOL_OPTIONS", tool_options);
+        }
+
+        command
+    }
+}
+
+fn spawn_error(command: &Command, source: io::Error) -> Error {
+    let program = command.get_program().to_string_lossy().into_owned();
+
+    match source.kind() {
+        io::ErrorKind::NotFound => Error::PklNotFound { tried: program },
+        _ => Error::Spawn { program, source },
+    }
+}
+
+impl Protocol {
+    pub fn new() -> Result<Self, Error> {
+        Self::builder().spawn()
+    }
+
+    /// Spawns `pkl server` from `program` with `args`, see [`ProtocolBuilder::command`].
+    pub fn with_command<I, S>(program: impl AsRef<OsStr>, args: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        Self::builder().command(program, args).spawn()
+    }
+
+    /// Talks to a server through `stdin`/`stdout` without a child process.
+    pub(crate) fn from_pipes(
+        stdin: impl Write + Send + 'static,
+        stdout: impl Read + Send + 'static,
+    ) -> Self {
+        Protocol {
+            child: None,
+            stdin: Box::new(stdin),
+            stdout: PipeReader::spawn(stdout),
+            observer: None,
+            requests: ServerRequests::default(),
+            pending: PendingResponses::default(),
+            duplicate_policy: DuplicateResponsePolicy::default(),
+            read_timeout: None,
+        }
+    }
+
+    /// Spawns `pkl server` with its temporary files in `dir`, see
+    /// [`ProtocolBuilder::temp_dir`].
+    pub fn with_temp_dir(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        Self::builder().temp_dir(dir).spawn()
+    }
 
-        Ok(Self {
-            child,
-            stdin,
-            stdout,
-        })
+    /// Spawns `pkl server` through `program` with `args`, see
+    /// [`ProtocolBuilder::wrapper`].
+    pub fn with_wrapper<I, S>(program: impl AsRef<OsStr>, args: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        Self::builder().wrapper(program, args).spawn()
+    }
+
+    pub fn builder() -> ProtocolBuilder {
+        ProtocolBuilder::default()
+    }
+
+    /// Size of the buffer responses are read into, 64 KiB by default. Larger buffers
+    /// take fewer reads for big results; smaller ones keep memory use down. Takes
+    /// effect from the next read of the pipe on.
+    pub fn with_read_buffer_size(self, size: usize) -> Self {
+        self.stdout.set_chunk_size(size);
+        self
+    }
+
+    /// Process id of the `pkl server` child, e.g. to apply resource limits. `None` once
+    /// the child has exited.
+    pub fn pid(&mut self) -> Option<u32> {
+        let child = self.child.as_mut()?;
+
+        match child.try_wait() {
+            Ok(None) => Some(child.id()),
+            _ => None,
+        }
+    }
+
+    pub fn set_duplicate_response_policy(&mut self, policy: DuplicateResponsePolicy) {
+        self.duplicate_policy = policy;
+    }
+
+    /// Fails receives with [`Error::Timeout`] when no message starts arriving within
+    /// `timeout`, so a server that stops responding can't block forever. A timed out
+    /// response may still arrive later, so discard the protocol after a timeout rather
+    /// than keep using it.
+    ///
+    /// Only the wait for the first byte of each message is covered: once a message
+    /// has started arriving, the rest of it is read without a timeout.
+    ///
+    /// Unlimited by default, except that [`crate::Evaluator`] waits for evaluations
+    /// with an [`EvalOpts::timeout`](crate::EvalOpts::timeout) only a little longer
+    /// than pkl itself allows them.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Runs `f` with `timeout` as the read timeout, unless one was set with
+    /// [`Protocol::set_read_timeout`].
+    pub(crate) fn with_read_timeout<T>(
+        &mut self,
+        timeout: Option<Duration>,
+        f: impl FnOnce(&mut Self) -> T,
+    ) -> T {
+        if self.read_timeout.is_some() || timeout.is_none() {
+            return f(self);
+        }
+
+        self.read_timeout = timeout;
+        let result = f(self);
+        self.read_timeout = None;
+
+        result
+    }
+
+    pub fn set_observer(&mut self, observer: impl MessageObserver + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Passes every `Log` message to `f` instead of emitting it through `tracing`.
+    pub fn on_log(&mut self, f: impl FnMut(LogEvent) + Send + 'static) {
+        self.requests.on_log(f);
     }
 
     #[instrument(skip_all, fields(id = request.request_id))]
@@ -47,8 +391,10 @@ impl Protocol {
         &mut self,
         request: CreateEvaluatorRequest,
     ) -> Result<CreateEvaluatorResponse, Error> {
+        let request_id = request.request_id;
+        self.pending.expect(request_id);
         self.send(request)?;
-        Ok(self.recv()?)
+        self.recv_until_response(request_id)
     }
 
     #[instrument(skip_all, fields(id = request.request_id))]
@@ -56,34 +402,802 @@ impl Protocol {
         &mut self,
         request: EvaluateRequest,
     ) -> Result<EvaluateResponse, Error> {
+        let request_id = request.request_id;
+        self.pending.expect(request_id);
+        self.send(request)?;
+        self.recv_until_response(request_id)
+    }
+
+    /// Evaluates `1 + 1` in a throwaway evaluator and returns whether pkl answered
+    /// every step within `timeout`, to detect a process that is alive but wedged.
+    ///
+    /// Answers that arrive after a `false` are dropped, and an evaluator created too
+    /// late is closed again, so the protocol stays usable. A process that keeps
+    /// failing pings is better recycled though.
+    #[instrument(skip(self))]
+    pub fn ping(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        match self.try_ping(deadline) {
+            Ok(responded) => responded,
+            Err(err) => {
+                debug!(?err, "ping failed");
+                false
+            }
+        }
+    }
+
+    fn try_ping(&mut self, deadline: Instant) -> Result<bool, Error> {
+        let allowed_modules = ["repl:".to_string()];
+
+        self.pending.expect(PING_REQUEST_ID);
+        self.send(CreateEvaluatorRequest {
+            request_id: PING_REQUEST_ID,
+            allowed_modules: Some(&allowed_modules),
+            ..Default::default()
+        })?;
+
+        let Some(response) =
+            self.recv_until_response_before::<CreateEvaluatorResponse>(PING_REQUEST_ID, deadline)?
+        else {
+            self.pending.abandon(PING_REQUEST_ID);
+            return Ok(false);
+        };
+        let Some(evaluator_id) = response.evaluator_id else {
+            return Ok(false);
+        };
+
+        self.pending.expect(PING_REQUEST_ID);
+        self.send(EvaluateRequest {
+            request_id: PING_REQUEST_ID,
+            evaluator_id,
+            module_uri: Uri::Url("repl:ping".to_string()),
+            module_text: Some(""),
+            expr: Some("1 + 1"),
+        })?;
+
+        let response =
+            self.recv_until_response_before::<EvaluateResponse>(PING_REQUEST_ID, deadline)?;
+        if response.is_none() {
+            self.pending.abandon(PING_REQUEST_ID);
+        }
+        self.close_evaluator(CloseEvaluator { evaluator_id })?;
+
+        Ok(response.is_some_and(|response| response.error.is_none()))
+    }
+
+    #[instrument(skip_all, fields(id = request.evaluator_id))]
+    pub fn close_evaluator(&mut self, request: CloseEvaluator) -> Result<(), Error> {
+        self.requests.unregister(request.evaluator_id);
+        self.send(request)
+    }
+
+    /// Serves `ReadResource` requests for `evaluator_id` from `readers`, which should
+    /// be the readers advertised when the evaluator was created.
+    pub fn register_resource_readers(
+        &mut self,
+        evaluator_id: i64,
+        readers: Vec<Arc<dyn ResourceReader>>,
+    ) {
+        self.requests
+            .register_resource_readers(evaluator_id, readers);
+    }
+
+    /// Serves `ReadModule` requests for `evaluator_id` from `readers`, which should be
+    /// the readers advertised when the evaluator was created.
+    pub fn register_module_readers(
+        &mut self,
+        evaluator_id: i64,
+        readers: Vec<Arc<dyn ModuleReader>>,
+    ) {
+        self.requests.register_module_readers(evaluator_id, readers);
+    }
+
+    pub(crate) fn requests_mut(&mut self) -> &mut ServerRequests {
+        &mut self.requests
+    }
+
+    /// Like [`Protocol::evaluate_request`], but copies the result bytes into `buf`
+    /// instead of allocating. Returns whether a result was written.
+    #[instrument(skip_all, fields(id = request.request_id))]
+    pub fn evaluate_request_into(
+        &mut self,
+        request: EvaluateRequest,
+        buf: &mut Vec<u8>,
+    ) -> Result<(EvaluateResponse, bool), Error> {
+        let request_id = request.request_id;
+        self.pending.expect(request_id);
         self.send(request)?;
-        Ok(self.recv()?)
+
+        if let Some(response) = self.pending.take(request_id) {
+            return Ok(result_into(response.try_into()?, buf));
+        }
+
+        loop {
+            self.wait_for_message()?;
+
+            let mut decoder = Decoder::new(&mut self.stdout);
+            let response = match decoder.decode_header()? {
+                EvaluateResponse::CODE => {
+                    let (mut response, has_result) = decoder.decode_evaluate_response_into(buf)?;
+                    // Lent to the response while it is routed, so that an answer to
+                    // another request keeps its result without a copy.
+                    response.result = has_result.then(|| mem::take(buf));
+                    response.into()
+                }
+                code => decoder.decode_body(code)?,
+            };
+
+            if let Some(response) = self.route(request_id, response)? {
+                return Ok(result_into(response.try_into()?, buf));
+            }
+        }
     }
 
+    /// Reads messages until the response of type `T` to `request_id` arrives, handling
+    /// any logs and server requests that are interleaved before it.
     #[instrument(skip_all, err(Debug))]
-    fn recv<T>(&mut self) -> Result<T, Error>
+    fn recv_until_response<T>(&mut self, request_id: u64) -> Result<T, Error>
     where
         T: Message + DeserializeOwned,
         T: TryFrom<Response, Error = Error>,
     {
-        Decoder::new(&mut self.stdout).decode_response_typed::<T>()
+        if let Some(response) = self.pending.take(request_id) {
+            return response.try_into();
+        }
+
+        loop {
+            self.wait_for_message()?;
+
+            let response = Decoder::new(&mut self.stdout).decode_response()?;
+
+            if let Some(response) = self.route(request_id, response)? {
+                return response.try_into();
+            }
+        }
+    }
+
+    /// Like [`Protocol::recv_until_response`], but gives up with `None` when no message
+    /// starts arriving before `deadline`.
+    fn recv_until_response_before<T>(
+        &mut self,
+        request_id: u64,
+        deadline: Instant,
+    ) -> Result<Option<T>, Error>
+    where
+        T: Message + DeserializeOwned,
+        T: TryFrom<Response, Error = Error>,
+    {
+        if let Some(response) = self.pending.take(request_id) {
+            return response.try_into().map(Some);
+        }
+
+        loop {
+            if !self
+                .stdout
+                .wait(deadline.saturating_duration_since(Instant::now()))
+            {
+                return Ok(None);
+            }
+
+            let response = Decoder::new(&mut self.stdout).decode_response()?;
+
+            if let Some(response) = self.route(request_id, response)? {
+                return response.try_into().map(Some);
+            }
+        }
+    }
+
+    /// Handles a message received while waiting for the answer to `request_id`,
+    /// returning it if it is that answer. Answers to other requests in flight are
+    /// kept for their own waiters, and server requests are answered.
+    fn route(&mut self, request_id: u64, response: Response) -> Result<Option<Response>, Error> {
+        let Some(id) = response.request_id() else {
+            self.handle(response)?;
+            return Ok(None);
+        };
+
+        if let Some(observer) = self.observer.as_deref_mut() {
+            observer::notify(observer, &response);
+        }
+
+        if self.pending.take_abandoned(id) {
+            debug!(id, "dropping late response to an abandoned request");
+
+            if let Response::CreateEvaluator(CreateEvaluatorResponse {
+                evaluator_id: Some(evaluator_id),
+                ..
+            }) = response
+            {
+                self.close_evaluator(CloseEvaluator { evaluator_id })?;
+            }
+
+            return Ok(None);
+        }
+
+        if id == request_id {
+            self.pending.done(id);
+            return Ok(Some(response));
+        }
+
+        if !self.pending.buffer(id, response) {
+            self.duplicate_policy.apply(id)?;
+        }
+
+        Ok(None)
+    }
+
+    /// Waits up to the read timeout, if one is set, for the next message to start.
+    fn wait_for_message(&mut self) -> Result<(), Error> {
+        match self.read_timeout {
+            Some(timeout) if !self.stdout.wait(timeout) => Err(Error::Timeout(timeout)),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle(&mut self, response: Response) -> Result<(), Error> {
+        if let Some(observer) = self.observer.as_deref_mut() {
+            observer::notify(observer, &response);
+        }
+
+        match self.requests.answer(response)? {
+            Some(frame) => self.write_frame(&frame),
+            None => Ok(()),
+        }
     }
 
     #[instrument(skip_all, err(Debug))]
     fn send<M: Message + Serialize>(&mut self, message: M) -> Result<(), Error> {
-        let mut serializer = Serializer::new(&mut self.stdin)
-            .with_struct_map()
-            .with_bytes(BytesMode::ForceAll);
+        self.send_raw(M::CODE, message)
+    }
+
+    /// Frames `body` as a message with `code` and sends it the same way typed
+    /// requests are sent, for messages this crate has no struct for yet.
+    ///
+    /// Nothing is tracked for raw messages: the caller picks request ids that don't
+    /// collide with those of [`crate::Evaluator`] and reads the answer with
+    /// [`Protocol::recv_any`].
+    pub fn send_raw(&mut self, code: u64, body: impl Serialize) -> Result<(), Error> {
+        let frame = encode_raw(code, body)?;
+        self.write_frame(&frame)
+    }
 
-        (M::CODE, message).serialize(&mut serializer)?;
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.stdin.write_all(frame)?;
         self.stdin.flush()?;
 
         Ok(())
     }
 
+    /// Reads the next message from the server, whatever it is, and hands it back
+    /// after notifying the observer instead of handling it.
+    pub fn recv_any(&mut self) -> Result<Response, Error> {
+        self.wait_for_message()?;
+
+        let response = Decoder::new(&mut self.stdout).decode_response()?;
+
+        if let Some(observer) = self.observer.as_deref_mut() {
+            observer::notify(observer, &response);
+        }
+
+        Ok(response)
+    }
+
+    /// Kills the `pkl server` process and waits for it to exit. Dropping a
+    /// `Protocol` does the same, ignoring errors.
+    #[instrument(skip_all)]
+    pub fn close(mut self) -> Result<(), Error> {
+        self.reap()
+    }
+
+    /// Asks the server to exit with `CloseExternalProcess` and gives it
+    /// [`SHUTDOWN_GRACE`] to do so before closing it like [`Protocol::close`]. The
+    /// child is reaped even if sending the message fails.
     #[instrument(skip_all)]
-    async fn close(mut self) -> Result<(), Error> {
-        let _ = self.child.kill();
+    pub fn shutdown(mut self) -> Result<(), Error> {
+        let sent = self.send(CloseExternalProcess {});
+        // Closing stdin as well tells the server no more requests are coming.
+        self.stdin = Box::new(io::sink());
+
+        if sent.is_ok() {
+            self.wait_for_exit(SHUTDOWN_GRACE)?;
+        }
+        self.reap()?;
+
+        sent
+    }
+
+    /// Waits up to `timeout` for the child to exit by itself, reaping it if it does.
+    fn wait_for_exit(&mut self, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+
+        while let Some(child) = self.child.as_mut() {
+            if child.try_wait()?.is_some() {
+                self.child = None;
+            } else if Instant::now() >= deadline {
+                break;
+            } else {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reap(&mut self) -> Result<(), Error> {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            child.wait()?;
+        }
+
         Ok(())
     }
 }
+
+impl PendingResponses {
+    /// Notes that a request with `request_id` was sent and awaits an answer.
+    pub(crate) fn expect(&mut self, request_id: u64) {
+        self.in_flight.insert(request_id);
+    }
+
+    /// Notes that the answer to `request_id` was handed to its waiter.
+    pub(crate) fn done(&mut self, request_id: u64) {
+        self.in_flight.remove(&request_id);
+    }
+
+    /// The answer to `request_id`, if it arrived while another request was awaited.
+    pub(crate) fn take(&mut self, request_id: u64) -> Option<Response> {
+        let response = self.buffered.remove(&request_id)?;
+        self.done(request_id);
+
+        Some(response)
+    }
+
+    /// Gives up on the answer to `request_id`, e.g. after a timeout, so that it is
+    /// dropped when it arrives late instead of being taken for the answer to a later
+    /// request with the same id.
+    pub(crate) fn abandon(&mut self, request_id: u64) {
+        self.in_flight.remove(&request_id);
+        *self.abandoned.entry(request_id).or_default() += 1;
+    }
+
+    /// Whether an answer to `request_id` is the late answer to an abandoned request,
+    /// which then no longer counts as abandoned.
+    pub(crate) fn take_abandoned(&mut self, request_id: u64) -> bool {
+        let Some(count) = self.abandoned.get_mut(&request_id) else {
+            return false;
+        };
+
+        *count -= 1;
+        if *count == 0 {
+            self.abandoned.remove(&request_id);
+        }
+
+        true
+    }
+
+    /// Keeps `response`, the answer to `request_id`, for its waiter. Returns `false`
+    /// when that request isn't in flight or was answered already, i.e. the response
+    /// is a duplicate.
+    pub(crate) fn buffer(&mut self, request_id: u64, response: Response) -> bool {
+        if !self.in_flight.contains(&request_id) || self.buffered.contains_key(&request_id) {
+            return false;
+        }
+
+        self.buffered.insert(request_id, response);
+        true
+    }
+}
+
+impl ServerRequests {
+    pub(crate) fn on_log(&mut self, f: impl FnMut(LogEvent) + Send + 'static) {
+        self.log_handler = Some(Box::new(f));
+    }
+
+    pub(crate) fn register_resource_readers(
+        &mut self,
+        evaluator_id: i64,
+        readers: Vec<Arc<dyn ResourceReader>>,
+    ) {
+        self.resource_readers.insert(evaluator_id, readers);
+    }
+
+    pub(crate) fn register_module_readers(
+        &mut self,
+        evaluator_id: i64,
+        readers: Vec<Arc<dyn ModuleReader>>,
+    ) {
+        self.module_readers.insert(evaluator_id, readers);
+    }
+
+    pub(crate) fn unregister(&mut self, evaluator_id: i64) {
+        self.resource_readers.remove(&evaluator_id);
+        self.module_readers.remove(&evaluator_id);
+    }
+
+    /// Handles a request from pkl, returning the encoded answer to send back if it
+    /// needs one.
+    #[instrument(skip_all, fields(message = response.name()))]
+    pub(crate) fn answer(&mut self, response: Response) -> Result<Option<Vec<u8>>, Error> {
+        let frame = match response {
+            Response::Log(log) => {
+                let event = LogEvent::from(log);
+
+                match (self.log_handler.as_mut(), event.level) {
+                    (Some(handler), _) => handler(event),
+                    (None, LogLevel::Trace) => trace!(uri = event.frame_uri, "{}", event.message),
+                    (None, LogLevel::Warn) => warn!(uri = event.frame_uri, "{}", event.message),
+                }
+                return Ok(None);
+            }
+            Response::ReadResource(request) => {
+                let (contents, error) = match self.read_resource(request.evaluator_id, &request.uri)
+                {
+                    Ok(contents) => (Some(contents), None),
+                    Err(error) => (None, Some(error)),
+                };
+
+                encode(ReadResourceResponse {
+                    request_id: request.request_id,
+                    evaluator_id: request.evaluator_id,
+                    contents: contents.as_deref(),
+                    error: error.as_deref(),
+                })
+            }
+            Response::ReadModule(request) => {
+                let (contents, error) = match self.read_module(request.evaluator_id, &request.uri) {
+                    Ok(contents) => (Some(contents), None),
+                    Err(error) => (None, Some(error)),
+                };
+
+                encode(ReadModuleResponse {
+                    request_id: request.request_id,
+                    evaluator_id: request.evaluator_id,
+                    contents: contents.as_deref(),
+                    error: error.as_deref(),
+                })
+            }
+            Response::ListResources(request) => {
+                let listing = self
+                    .resource_reader(request.evaluator_id, &request.uri)
+                    .and_then(|reader| reader.list(&request.uri));
+                let (path_elements, error) = match listing {
+                    Ok(elements) => (Some(elements), None),
+                    Err(error) => (None, Some(error)),
+                };
+
+                encode(ListResourcesResponse {
+                    request_id: request.request_id,
+                    evaluator_id: request.evaluator_id,
+                    path_elements: path_elements.as_deref(),
+                    error: error.as_deref(),
+                })
+            }
+            Response::ListModules(request) => {
+                let listing = self
+                    .module_reader(request.evaluator_id, &request.uri)
+                    .and_then(|reader| reader.list(&request.uri));
+                let (path_elements, error) = match listing {
+                    Ok(elements) => (Some(elements), None),
+                    Err(error) => (None, Some(error)),
+                };
+
+                encode(ListModulesResponse {
+                    request_id: request.request_id,
+                    evaluator_id: request.evaluator_id,
+                    path_elements: path_elements.as_deref(),
+                    error: error.as_deref(),
+                })
+            }
+            Response::InitializeModuleReader(request) => {
+                let spec = self
+                    .module_readers
+                    .values()
+                    .flatten()
+                    .find(|reader| reader.scheme() == request.scheme)
+                    .map(|reader| reader::module_spec(reader.as_ref()));
+
+                encode(InitializeModuleReaderResponse {
+                    request_id: request.request_id,
+                    spec: spec.as_ref(),
+                })
+            }
+            Response::InitializeResourceReader(request) => {
+                let spec = self
+                    .resource_readers
+                    .values()
+                    .flatten()
+                    .find(|reader| reader.scheme() == request.scheme)
+                    .map(|reader| reader::resource_spec(reader.as_ref()));
+
+                encode(InitializeResourceReaderResponse {
+                    request_id: request.request_id,
+                    spec: spec.as_ref(),
+                })
+            }
+            Response::CloseExternalProcess(_) => {
+                debug!("ignoring request to close external process");
+                return Ok(None);
+            }
+            response => return Err(Error::InvalidResponse(response.name())),
+        }?;
+
+        Ok(Some(frame))
+    }
+
+    /// The module reader registered for `evaluator_id` that serves `uri`.
+    fn module_reader(&self, evaluator_id: i64, uri: &str) -> Result<&dyn ModuleReader, String> {
+        self.module_readers
+            .get(&evaluator_id)
+            .and_then(|readers| {
+                readers
+                    .iter()
+                    .find(|reader| reader::matches_scheme(uri, reader.scheme()))
+            })
+            .map(|reader| reader.as_ref())
+            .ok_or_else(|| no_reader(uri))
+    }
+
+    /// The resource reader registered for `evaluator_id` that serves `uri`.
+    fn resource_reader(&self, evaluator_id: i64, uri: &str) -> Result<&dyn ResourceReader, String> {
+        self.resource_readers
+            .get(&evaluator_id)
+            .and_then(|readers| {
+                readers
+                    .iter()
+                    .find(|reader| reader::matches_scheme(uri, reader.scheme()))
+            })
+            .map(|reader| reader.as_ref())
+            .ok_or_else(|| no_reader(uri))
+    }
+
+    fn read_module(&self, evaluator_id: i64, uri: &str) -> Result<String, String> {
+        self.module_reader(evaluator_id, uri)?.read(uri)
+    }
+
+    fn read_resource(&self, evaluator_id: i64, uri: &str) -> Result<Vec<u8>, String> {
+        let contents = self.resource_reader(evaluator_id, uri)?.read(uri)?;
+
+        if contents.len() > MAX_RESOURCE_LEN {
+            return Err(format!(
+                "resource {uri} is {} bytes, exceeding the limit of {MAX_RESOURCE_LEN} bytes",
+                contents.len()
+            ));
+        }
+
+        Ok(contents)
+    }
+}
+
+impl Drop for Protocol {
+    fn drop(&mut self) {
+        let _ = self.reap();
+    }
+}
+
+/// Encodes `message` the way pkl expects: `[code, body]` with structs as maps.
+pub(crate) fn encode<M: Message + Serialize>(message: M) -> Result<Vec<u8>, Error> {
+    encode_raw(M::CODE, message)
+}
+
+pub(crate) fn encode_raw(code: u64, body: impl Serialize) -> Result<Vec<u8>, Error> {
+    let mut frame = Vec::new();
+    let mut serializer = Serializer::new(&mut frame)
+        .with_struct_map()
+        .with_bytes(BytesMode::ForceAll);
+
+    (code, body).serialize(&mut serializer)?;
+
+    Ok(frame)
+}
+
+/// Moves the result of `response` into `buf`, returning whether it had one.
+fn result_into(mut response: EvaluateResponse, buf: &mut Vec<u8>) -> (EvaluateResponse, bool) {
+    match response.result.take() {
+        Some(result) => {
+            *buf = result;
+            (response, true)
+        }
+        None => {
+            buf.clear();
+            (response, false)
+        }
+    }
+}
+
+fn no_reader(uri: &str) -> String {
+    format!("no reader registered for {uri}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        mock::{self, MockProtocol},
+        server::Value,
+        value,
+    };
+
+    /// The parts of a sent `CloseEvaluator` the tests look at.
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct SentClose {
+        evaluator_id: i64,
+    }
+
+    fn created(request_id: u64, evaluator_id: i64) -> Vec<u8> {
+        let created = value!({ "requestId" => request_id, "evaluatorId" => evaluator_id });
+        mock::message(CreateEvaluatorResponse::CODE, created).unwrap()
+    }
+
+    fn evaluated(request_id: u64, evaluator_id: i64, result: &[u8]) -> Vec<u8> {
+        let result = Value::Bytes(result.to_vec());
+        let evaluated = value!({
+            "requestId" => request_id,
+            "evaluatorId" => evaluator_id,
+            "result" => result,
+        });
+        mock::message(EvaluateResponse::CODE, evaluated).unwrap()
+    }
+
+    #[test]
+    fn late_ping_responses_are_dropped() {
+        let (stdout, mut server) = io::pipe().unwrap();
+        let (mut proto, mock) = MockProtocol::from_reader(stdout);
+
+        assert!(!proto.ping(Duration::from_millis(20)));
+
+        server.write_all(&created(PING_REQUEST_ID, 5)).unwrap();
+        server.write_all(&created(PING_REQUEST_ID, 6)).unwrap();
+        server
+            .write_all(&evaluated(PING_REQUEST_ID, 6, b"\x02"))
+            .unwrap();
+
+        assert!(proto.ping(Duration::from_secs(5)));
+        assert!(proto.pending.abandoned.is_empty());
+
+        let closed: Vec<_> = mock
+            .sent_messages::<SentClose>(CloseEvaluator::CODE)
+            .unwrap()
+            .into_iter()
+            .map(|close| close.evaluator_id)
+            .collect();
+        assert_eq!(closed, [5, 6]);
+    }
+
+    #[test]
+    fn evaluate_into_keeps_answers_to_other_requests() {
+        let mut transcript = created(7, 3);
+        transcript.extend(evaluated(8, 2, b"\xa5other"));
+        transcript.extend(evaluated(1, 1, b"\xa2ok"));
+        let (mut proto, _mock) = MockProtocol::new(transcript);
+        proto.pending.expect(7);
+        proto.pending.expect(8);
+
+        let mut buf = Vec::new();
+        let request = EvaluateRequest {
+            request_id: 1,
+            evaluator_id: 1,
+            module_uri: Uri::default(),
+            module_text: None,
+            expr: None,
+        };
+        let (response, has_result) = proto.evaluate_request_into(request, &mut buf).unwrap();
+
+        assert_eq!(response.request_id, 1);
+        assert!(has_result);
+        assert_eq!(buf, b"\xa2ok");
+        assert!(matches!(
+            proto.pending.take(7),
+            Some(Response::CreateEvaluator(CreateEvaluatorResponse {
+                evaluator_id: Some(3),
+                ..
+            }))
+        ));
+        assert!(matches!(
+            proto.pending.take(8),
+            Some(Response::Evaluate(EvaluateResponse { result: Some(result), .. }))
+                if result == b"\xa5other"
+        ));
+    }
+
+    #[test]
+    fn read_timeout_applies_to_a_single_call() {
+        let (stdout, _server) = io::pipe().unwrap();
+        let (mut proto, _mock) = MockProtocol::from_reader(stdout);
+        let timeout = Duration::from_millis(20);
+
+        let result = proto.with_read_timeout(Some(timeout), Protocol::recv_any);
+
+        assert!(matches!(result, Err(Error::Timeout(t)) if t == timeout));
+        assert_eq!(proto.read_timeout, None);
+    }
+
+    #[test]
+    fn explicit_read_timeout_takes_precedence() {
+        let (stdout, _server) = io::pipe().unwrap();
+        let (mut proto, _mock) = MockProtocol::from_reader(stdout);
+        let timeout = Duration::from_millis(20);
+        proto.set_read_timeout(Some(timeout));
+
+        let result = proto.with_read_timeout(Some(Duration::from_secs(3600)), Protocol::recv_any);
+
+        assert!(matches!(result, Err(Error::Timeout(t)) if t == timeout));
+        assert_eq!(proto.read_timeout, Some(timeout));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shutdown_lets_the_server_exit_by_itself() {
+        let marker = std::env::temp_dir().join(format!("rust-pkl-shutdown-{}", std::process::id()));
+        // Exits once stdin is closed, leaving the marker behind unless it was killed.
+        let script = r#"cat >/dev/null; touch "$0""#;
+        let proto = Protocol::builder()
+            .command(
+                "sh",
+                [OsStr::new("-c"), OsStr::new(script), marker.as_os_str()],
+            )
+            .spawn()
+            .unwrap();
+
+        proto.shutdown().unwrap();
+
+        assert!(marker.exists());
+        std::fs::remove_file(marker).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn child_puts_temporary_files_in_temp_dir() {
+        let dir = std::env::temp_dir().join(format!("rust-pkl-temp-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script =
+            r#"mktemp >/dev/null; printf %s "$JAVA_TOOL_OPTIONS" >"$TMPDIR/java"; cat >/dev/null"#;
+        let proto = Protocol::builder()
+            .command("sh", ["-c", script])
+            .temp_dir(&dir)
+            .spawn()
+            .unwrap();
+
+        proto.shutdown().unwrap();
+
+        let created = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != "java")
+            .count();
+        let java = std::fs::read_to_string(dir.join("java")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(created, 1);
+        assert!(java.contains(&format!("-Djava.io.tmpdir={}", dir.display())));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn messages_pass_through_a_wrapper() {
+        // The wrapper runs the rest of its arguments, and the "server" echoes stdin.
+        let mut proto = Protocol::builder()
+            .wrapper("sh", ["-c", r#"exec "$@""#, "sh"])
+            .command("sh", ["-c", "exec cat"])
+            .spawn()
+            .unwrap();
+
+        proto
+            .send_raw(
+                EvaluateResponse::CODE,
+                value!({ "requestId" => 3, "evaluatorId" => 1 }),
+            )
+            .unwrap();
+
+        assert!(matches!(
+            proto.recv_any().unwrap(),
+            Response::Evaluate(EvaluateResponse { request_id: 3, .. })
+        ));
+        proto.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::client::{ClientModuleReader, ClientResourceReader, PathElement};
+
+/// Upper bound on the contents a [`ResourceReader`] may return for one resource.
+/// Larger contents are reported to pkl as a read error instead of being sent.
+pub const MAX_RESOURCE_LEN: usize = 64 * 1024 * 1024;
+
+/// Serves `read("<scheme>:...")` for a custom scheme from Rust. Register readers in
+/// [`crate::EvalOpts::client_resource_readers`]; the scheme must also be allowed by
+/// `allowed_resources`.
+///
+/// The pkl protocol has no chunked reads, so a resource is always sent to pkl as a
+/// single message and both sides hold it in memory in full. Contents larger than
+/// [`MAX_RESOURCE_LEN`] fail the read rather than being sent.
+pub trait ResourceReader: Send + Sync {
+    fn scheme(&self) -> &str;
+
+    /// Whether URIs of this scheme are hierarchical (`scheme:/a/b`), which lets pkl
+    /// resolve relative paths against them.
+    fn has_hierarchical_uris(&self) -> bool {
+        false
+    }
+
+    fn is_globbable(&self) -> bool {
+        false
+    }
+
+    /// Returns the contents of `uri`, or an error message that pkl raises as an
+    /// evaluation error.
+    fn read(&self, uri: &str) -> Result<Vec<u8>, String>;
+
+    /// Lists the entries under `uri` for glob reads. Readers that are globbable must
+    /// implement this; the default fails the listing.
+    fn list(&self, uri: &str) -> Result<Vec<PathElement>, String> {
+        Err(no_listing(uri))
+    }
+}
+
+/// Serves `import "<scheme>:..."` for a custom scheme from Rust, e.g. modules
+/// generated in memory. Register readers in
+/// [`crate::EvalOpts::client_module_readers`]; the scheme must also be allowed by
+/// `allowed_modules`.
+pub trait ModuleReader: Send + Sync {
+    fn scheme(&self) -> &str;
+
+    /// Whether URIs of this scheme are hierarchical (`scheme:/a/b`), which lets pkl
+    /// resolve relative imports against them.
+    fn has_hierarchical_uris(&self) -> bool {
+        false
+    }
+
+    fn is_globbable(&self) -> bool {
+        false
+    }
+
+    /// Whether modules of this scheme are considered local, which lets them import
+    /// other local modules such as files.
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    /// Returns the source of the module at `uri`, or an error message that pkl
+    /// raises as an evaluation error.
+    fn read(&self, uri: &str) -> Result<String, String>;
+
+    /// Lists the entries under `uri` for glob imports. Readers that are globbable
+    /// must implement this; the default fails the listing.
+    fn list(&self, uri: &str) -> Result<Vec<PathElement>, String> {
+        Err(no_listing(uri))
+    }
+}
+
+/// Serves the resources of one scheme from `EvalOpts::inline_resources`.
+pub(crate) struct InlineResources {
+    pub(crate) scheme: String,
+    pub(crate) resources: Arc<HashMap<String, Vec<u8>>>,
+}
+
+impl ResourceReader for InlineResources {
+    fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    fn read(&self, uri: &str) -> Result<Vec<u8>, String> {
+        self.resources
+            .get(uri)
+            .cloned()
+            .ok_or_else(|| format!("no inline resource {uri}"))
+    }
+}
+
+pub(crate) fn module_spec(reader: &dyn ModuleReader) -> ClientModuleReader {
+    ClientModuleReader {
+        scheme: reader.scheme().to_string(),
+        has_hierarchical_uris: reader.has_hierarchical_uris(),
+        is_globbable: reader.is_globbable(),
+        is_local: reader.is_local(),
+    }
+}
+
+pub(crate) fn resource_spec(reader: &dyn ResourceReader) -> ClientResourceReader {
+    ClientResourceReader {
+        scheme: reader.scheme().to_string(),
+        has_hierarchical_uris: reader.has_hierarchical_uris(),
+        is_globbable: reader.is_globbable(),
+    }
+}
+
+fn no_listing(uri: &str) -> String {
+    format!("listing {uri} is not supported")
+}
+
+/// Whether `uri` belongs to `scheme`.
+pub(crate) fn matches_scheme(uri: &str, scheme: &str) -> bool {
+    uri.split_once(':').is_some_and(|(s, _)| s == scheme)
+}
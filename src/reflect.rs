@@ -0,0 +1,174 @@
+use crate::{
+    client::Uri,
+    errors::ValueError,
+    server::{Object, Value},
+};
+
+/// Declared shape of a module, as returned by [`crate::Evaluator::module_info`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleInfo {
+    /// Name from the `module` clause, or derived from the file name without one.
+    pub name: String,
+    pub doc_comment: Option<String>,
+    /// Properties declared by the module itself, excluding inherited and `local` ones.
+    pub properties: Vec<PropertyInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertyInfo {
+    pub name: String,
+    pub doc_comment: Option<String>,
+    /// Declared type as written in Pkl, e.g. `Listing<String>?`. `None` when the
+    /// property has no type annotation.
+    pub ty: Option<String>,
+}
+
+/// Where a property is declared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    pub module_uri: String,
+    /// 1-based line of the declaration.
+    pub line: u64,
+    /// 1-based column of the declaration.
+    pub column: u64,
+}
+
+/// Renders a `reflect.Type` back into Pkl syntax.
+const TYPE_NAME: &str = r#"
+local function typeName(t: reflect.Type): String? =
+  if (t is reflect.DeclaredType)
+    t.referent.name + if (t.typeArguments.isEmpty) "" else
+      "<" + t.typeArguments.map((arg) -> typeName(arg) ?? "unknown").join(", ") + ">"
+  else if (t is reflect.NullableType) (typeName(t.member) ?? "unknown") + "?"
+  else if (t is reflect.UnionType) t.members.map((m) -> typeName(m) ?? "unknown").join("|")
+  else if (t is reflect.StringLiteralType) "\"\(t.value)\""
+  else if (t is reflect.FunctionType)
+    "(" + t.parameterTypes.map((p) -> typeName(p) ?? "unknown").join(", ") + ") -> "
+      + (typeName(t.returnType) ?? "unknown")
+  else if (t is reflect.ModuleType) "module"
+  else if (t is reflect.NothingType) "nothing"
+  else if (t is reflect.TypeVariable) t.name
+  else null
+"#;
+
+/// Module text that imports `uri` and describes it through `pkl:reflect`.
+pub(crate) fn module_info_text(uri: &Uri) -> String {
+    format!(
+        r#"import "pkl:reflect"
+
+local target = reflect.Module(import("{uri}"))
+{TYPE_NAME}
+name = target.name
+docComment = target.docComment
+properties = target.moduleClass.properties.values.map((p) -> new Dynamic {{
+  name = p.name
+  docComment = p.docComment
+  type = typeName(p.type)
+}}).toListing()
+"#,
+        uri = escape(&uri.to_string()),
+    )
+}
+
+/// Module text that imports `uri` and lists the declaration site of each property
+/// the module itself declares.
+pub(crate) fn locations_text(uri: &Uri) -> String {
+    format!(
+        r#"import "pkl:reflect"
+
+local target = reflect.Module(import("{uri}"))
+
+locations = target.moduleClass.properties.values.map((p) -> new Dynamic {{
+  name = p.name
+  moduleUri = p.location.displayUri
+  line = p.location.line
+  column = p.location.column
+}}).toListing()
+"#,
+        uri = escape(&uri.to_string()),
+    )
+}
+
+/// Escapes `s` for use inside a single-line Pkl string literal.
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl TryFrom<Value> for ModuleInfo {
+    type Error = ValueError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let mut object = into_object(value)?;
+        let properties = match take(&mut object, "properties")? {
+            Value::Listing(properties) => properties
+                .into_iter()
+                .map(PropertyInfo::try_from)
+                .collect::<Result<_, _>>()?,
+            _ => return Err(ValueError::UnexpectedValue),
+        };
+
+        Ok(ModuleInfo {
+            name: take(&mut object, "name")?.try_into()?,
+            doc_comment: optional_string(take(&mut object, "docComment")?)?,
+            properties,
+        })
+    }
+}
+
+impl TryFrom<Value> for PropertyInfo {
+    type Error = ValueError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let mut object = into_object(value)?;
+
+        Ok(PropertyInfo {
+            name: take(&mut object, "name")?.try_into()?,
+            doc_comment: optional_string(take(&mut object, "docComment")?)?,
+            ty: optional_string(take(&mut object, "type")?)?,
+        })
+    }
+}
+
+/// Converts the result of [`locations_text`] into `(property, location)` pairs.
+pub(crate) fn parse_locations(value: Value) -> Result<Vec<(String, SourceLocation)>, ValueError> {
+    let mut object = into_object(value)?;
+    let Value::Listing(locations) = take(&mut object, "locations")? else {
+        return Err(ValueError::UnexpectedValue);
+    };
+
+    locations
+        .into_iter()
+        .map(|location| {
+            let mut object = into_object(location)?;
+            let name = take(&mut object, "name")?.try_into()?;
+            let location = SourceLocation {
+                module_uri: take(&mut object, "moduleUri")?.try_into()?,
+                line: take(&mut object, "line")?.try_into()?,
+                column: take(&mut object, "column")?.try_into()?,
+            };
+
+            Ok((name, location))
+        })
+        .collect()
+}
+
+fn into_object(value: Value) -> Result<Object, ValueError> {
+    match value {
+        Value::Object(object) => Ok(object),
+        _ => Err(ValueError::UnexpectedValue),
+    }
+}
+
+fn take(object: &mut Object, name: &str) -> Result<Value, ValueError> {
+    object
+        .properties
+        .shift_remove(name)
+        .ok_or(ValueError::UnexpectedValue)
+}
+
+fn optional_string(value: Value) -> Result<Option<String>, ValueError> {
+    match value {
+        Value::Null => Ok(None),
+        value => value.try_into().map(Some),
+    }
+}
@@ -1,18 +1,38 @@
-use std::collections::HashMap;
+#[cfg(any(feature = "json", feature = "yaml"))]
+use std::io::Write;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    hash::Hash,
+    iter,
+    str::FromStr,
+    time::Duration,
+};
 
-use serde::Deserialize;
+use indexmap::IndexMap;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
 use serde_with::skip_serializing_none;
 
-use crate::{errors::ValueError, protocol::Message};
+use crate::{
+    errors::{Error, ValueError},
+    protocol::Message,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Object {
     pub class_name: String,
     pub module_uri: String,
-    pub properties: HashMap<String, Value>,
+    /// Properties in the order pkl sent them, which is their declaration order.
+    pub properties: IndexMap<String, Value>,
+    /// Entries declared as `[key] = value`, e.g. in a `Dynamic`, in declaration order.
+    pub entries: Vec<(Value, Value)>,
+    /// Elements declared without a name, e.g. in a `Dynamic`, in declaration order.
+    pub elements: Vec<Value>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Null,
     Int(i64),
@@ -20,13 +40,1027 @@ pub enum Value {
     Float(f64),
     Bool(bool),
     String(String),
+    Bytes(Vec<u8>),
+    Duration {
+        value: f64,
+        unit: DurationUnit,
+    },
+    DataSize {
+        value: f64,
+        unit: DataSizeUnit,
+    },
+    /// A Pkl `IntSeq`, with `end` inclusive.
+    IntSeq {
+        start: i64,
+        end: i64,
+        step: i64,
+    },
+    /// Source pattern of a Pkl `Regex`, in Java regex syntax.
+    Regex(String),
     Function,
+    /// A Pkl class, identified by name and the URI of its module.
+    Class {
+        name: String,
+        module_uri: String,
+    },
+    /// A Pkl typealias, identified by name and the URI of its module.
+    TypeAlias {
+        name: String,
+        module_uri: String,
+    },
     Object(Object),
+    Pair(Box<Value>, Box<Value>),
     Array(Vec<Value>),
+    /// Elements of a Pkl `Listing`, kept apart from `Array` so typed listings can be
+    /// told from plain lists.
+    Listing(Vec<Value>),
+    /// Elements of a Pkl `Set`, in the order pkl sent them.
+    Set(Vec<Value>),
     Map(Vec<(Value, Value)>),
     Mapping(Vec<(Value, Value)>),
 }
 
+/// Unit of a Pkl `Duration`, as written after the `.` in e.g. `5.min`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DurationUnit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+    Days,
+}
+
+impl DurationUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DurationUnit::Nanoseconds => "ns",
+            DurationUnit::Microseconds => "us",
+            DurationUnit::Milliseconds => "ms",
+            DurationUnit::Seconds => "s",
+            DurationUnit::Minutes => "min",
+            DurationUnit::Hours => "h",
+            DurationUnit::Days => "d",
+        }
+    }
+
+    /// Length of one unit in seconds.
+    pub fn seconds(&self) -> f64 {
+        match self {
+            DurationUnit::Nanoseconds => 1e-9,
+            DurationUnit::Microseconds => 1e-6,
+            DurationUnit::Milliseconds => 1e-3,
+            DurationUnit::Seconds => 1.0,
+            DurationUnit::Minutes => 60.0,
+            DurationUnit::Hours => 60.0 * 60.0,
+            DurationUnit::Days => 24.0 * 60.0 * 60.0,
+        }
+    }
+}
+
+impl FromStr for DurationUnit {
+    type Err = ValueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "ns" => DurationUnit::Nanoseconds,
+            "us" => DurationUnit::Microseconds,
+            "ms" => DurationUnit::Milliseconds,
+            "s" => DurationUnit::Seconds,
+            "min" => DurationUnit::Minutes,
+            "h" => DurationUnit::Hours,
+            "d" => DurationUnit::Days,
+            _ => return Err(ValueError::UnexpectedValue),
+        })
+    }
+}
+
+impl fmt::Display for DurationUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Unit of a Pkl `DataSize`, as written after the `.` in e.g. `512.mib`. Units
+/// ending in `ib` are binary (powers of 1024), the others decimal (powers of 1000).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataSizeUnit {
+    Bytes,
+    Kilobytes,
+    Kibibytes,
+    Megabytes,
+    Mebibytes,
+    Gigabytes,
+    Gibibytes,
+    Terabytes,
+    Tebibytes,
+    Petabytes,
+    Pebibytes,
+}
+
+impl DataSizeUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DataSizeUnit::Bytes => "b",
+            DataSizeUnit::Kilobytes => "kb",
+            DataSizeUnit::Kibibytes => "kib",
+            DataSizeUnit::Megabytes => "mb",
+            DataSizeUnit::Mebibytes => "mib",
+            DataSizeUnit::Gigabytes => "gb",
+            DataSizeUnit::Gibibytes => "gib",
+            DataSizeUnit::Terabytes => "tb",
+            DataSizeUnit::Tebibytes => "tib",
+            DataSizeUnit::Petabytes => "pb",
+            DataSizeUnit::Pebibytes => "pib",
+        }
+    }
+
+    /// Number of bytes in one unit.
+    pub fn bytes(&self) -> u64 {
+        match self {
+            DataSizeUnit::Bytes => 1,
+            DataSizeUnit::Kilobytes => 1000,
+            DataSizeUnit::Kibibytes => 1 << 10,
+            DataSizeUnit::Megabytes => 1000_u64.pow(2),
+            DataSizeUnit::Mebibytes => 1 << 20,
+            DataSizeUnit::Gigabytes => 1000_u64.pow(3),
+            DataSizeUnit::Gibibytes => 1 << 30,
+            DataSizeUnit::Terabytes => 1000_u64.pow(4),
+            DataSizeUnit::Tebibytes => 1 << 40,
+            DataSizeUnit::Petabytes => 1000_u64.pow(5),
+            DataSizeUnit::Pebibytes => 1 << 50,
+        }
+    }
+}
+
+impl FromStr for DataSizeUnit {
+    type Err = ValueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "b" => DataSizeUnit::Bytes,
+            "kb" => DataSizeUnit::Kilobytes,
+            "kib" => DataSizeUnit::Kibibytes,
+            "mb" => DataSizeUnit::Megabytes,
+            "mib" => DataSizeUnit::Mebibytes,
+            "gb" => DataSizeUnit::Gigabytes,
+            "gib" => DataSizeUnit::Gibibytes,
+            "tb" => DataSizeUnit::Terabytes,
+            "tib" => DataSizeUnit::Tebibytes,
+            "pb" => DataSizeUnit::Petabytes,
+            "pib" => DataSizeUnit::Pebibytes,
+            _ => return Err(ValueError::UnexpectedValue),
+        })
+    }
+}
+
+impl fmt::Display for DataSizeUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Integers compare by numeric value regardless of whether they were decoded as
+/// `Int` or `Uint`, since pkl encodes non-negative integers as unsigned.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null) | (Value::Function, Value::Function) => true,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Uint(a), Value::Uint(b)) => a == b,
+            (Value::Int(i), Value::Uint(u)) | (Value::Uint(u), Value::Int(i)) => {
+                u64::try_from(*i).is_ok_and(|i| i == *u)
+            }
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::String(a), Value::String(b)) | (Value::Regex(a), Value::Regex(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            (
+                Value::Duration { value, unit },
+                Value::Duration {
+                    value: other_value,
+                    unit: other_unit,
+                },
+            ) => value == other_value && unit == other_unit,
+            (
+                Value::DataSize { value, unit },
+                Value::DataSize {
+                    value: other_value,
+                    unit: other_unit,
+                },
+            ) => value == other_value && unit == other_unit,
+            (Value::Object(a), Value::Object(b)) => a == b,
+            (
+                Value::IntSeq { start, end, step },
+                Value::IntSeq {
+                    start: other_start,
+                    end: other_end,
+                    step: other_step,
+                },
+            ) => (start, end, step) == (other_start, other_end, other_step),
+            (
+                Value::Class { name, module_uri },
+                Value::Class {
+                    name: other_name,
+                    module_uri: other_module_uri,
+                },
+            )
+            | (
+                Value::TypeAlias { name, module_uri },
+                Value::TypeAlias {
+                    name: other_name,
+                    module_uri: other_module_uri,
+                },
+            ) => name == other_name && module_uri == other_module_uri,
+            (Value::Pair(a, b), Value::Pair(c, d)) => a == c && b == d,
+            (Value::Array(a), Value::Array(b))
+            | (Value::Listing(a), Value::Listing(b))
+            | (Value::Set(a), Value::Set(b)) => a == b,
+            (Value::Map(a), Value::Map(b)) | (Value::Mapping(a), Value::Mapping(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+macro_rules! impl_value_from {
+    ($($ty:ty => $variant:ident),+) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(value: $ty) -> Self {
+                    Value::$variant(value.into())
+                }
+            }
+        )+
+    };
+}
+
+impl_value_from!(
+    i32 => Int,
+    i64 => Int,
+    u32 => Uint,
+    u64 => Uint,
+    f64 => Float,
+    bool => Bool,
+    &str => String,
+    String => String,
+    Vec<Value> => Array
+);
+
+/// Builds a [`Value`] tree, mainly for writing expected values in tests.
+///
+/// - `value!(null)` is `Value::Null`
+/// - `value!([a, b, ...])` is a `Value::Array`
+/// - `value!({ k => v, ... })` is a `Value::Map`
+/// - `value!(Class { name => v, ... })` is a `Value::Object` of class `Class` with
+///   those properties and an empty module URI
+/// - `value!(Class @ "file:///app.pkl" { name => v, ... })` is the same object with
+///   that module URI, as decoded objects have
+/// - anything else is passed to `Value::from`
+///
+/// Elements, keys and values must be single tokens or nested brackets/braces, so
+/// wrap anything longer in parentheses, e.g. `value!([(-1), (x + 1)])`. Property
+/// names are identifiers.
+#[macro_export]
+macro_rules! value {
+    (null) => {
+        $crate::server::Value::Null
+    };
+    ([ $($element:tt),* $(,)? ]) => {
+        $crate::server::Value::Array(vec![$($crate::value!($element)),*])
+    };
+    ({ $($key:tt => $value:tt),* $(,)? }) => {
+        $crate::server::Value::Map(vec![$(($crate::value!($key), $crate::value!($value))),*])
+    };
+    ($class:ident { $($name:ident => $value:tt),* $(,)? }) => {
+        $crate::value!($class @ "" { $($name => $value),* })
+    };
+    ($class:ident @ $module_uri:literal { $($name:ident => $value:tt),* $(,)? }) => {
+        $crate::server::Value::Object($crate::server::Object {
+            class_name: stringify!($class).to_string(),
+            module_uri: $module_uri.to_string(),
+            properties: [$((stringify!($name).to_string(), $crate::value!($value))),*]
+                .into_iter()
+                .collect(),
+            entries: vec![],
+            elements: vec![],
+        })
+    };
+    ($other:expr) => {
+        $crate::server::Value::from($other)
+    };
+}
+
+/// An object with only elements serializes as a sequence. Otherwise it is a map of
+/// its properties, followed by its entries and its elements keyed by index.
+impl Serialize for Object {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.properties.is_empty() && self.entries.is_empty() && !self.elements.is_empty() {
+            return serializer.collect_seq(&self.elements);
+        }
+
+        let len = self.properties.len() + self.entries.len() + self.elements.len();
+        let mut map = serializer.serialize_map(Some(len))?;
+
+        for (name, value) in &self.properties {
+            map.serialize_entry(name, value)?;
+        }
+
+        for (key, value) in &self.entries {
+            map.serialize_entry(key, value)?;
+        }
+
+        for (i, value) in self.elements.iter().enumerate() {
+            map.serialize_entry(&i, value)?;
+        }
+
+        map.end()
+    }
+}
+
+/// Maps and mappings whose keys are all strings serialize as maps. Other keys can't
+/// be represented in most formats, so those serialize as a sequence of `[key, value]`
+/// pairs instead.
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Null | Value::Function => serializer.serialize_unit(),
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::Uint(u) => serializer.serialize_u64(*u),
+            Value::Float(f) => serializer.serialize_f64(*f),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::String(s) | Value::Regex(s) => serializer.serialize_str(s),
+            Value::Class { name, .. } | Value::TypeAlias { name, .. } => {
+                serializer.serialize_str(name)
+            }
+            Value::Bytes(bytes) => serializer.serialize_bytes(bytes),
+            Value::Duration { value, unit } => {
+                serializer.collect_str(&format_args!("{value}.{unit}"))
+            }
+            Value::DataSize { value, unit } => {
+                serializer.collect_str(&format_args!("{value}.{unit}"))
+            }
+            Value::IntSeq { start, end, step } => {
+                let mut map = serializer.serialize_map(Some(3))?;
+                map.serialize_entry("start", start)?;
+                map.serialize_entry("end", end)?;
+                map.serialize_entry("step", step)?;
+                map.end()
+            }
+            Value::Object(object) => object.serialize(serializer),
+            Value::Pair(first, second) => serializer.collect_seq([first, second]),
+            Value::Array(values) | Value::Listing(values) | Value::Set(values) => {
+                serializer.collect_seq(values)
+            }
+            Value::Map(entries) | Value::Mapping(entries) if has_string_keys(entries) => {
+                serializer.collect_map(entries.iter().map(|(key, value)| (key, value)))
+            }
+            Value::Map(entries) | Value::Mapping(entries) => {
+                serializer.collect_seq(entries.iter().map(|(key, value)| [key, value]))
+            }
+        }
+    }
+}
+
+/// Serializes like the wrapped value, except that objects (including nested ones)
+/// carry their class name and module URI as `@type` and `@module` keys.
+#[cfg(feature = "json")]
+struct WithTypeInfo<'a, T>(&'a T);
+
+#[cfg(feature = "json")]
+impl Serialize for WithTypeInfo<'_, Object> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let object = self.0;
+        let len = object.properties.len() + object.entries.len() + object.elements.len() + 2;
+        let mut map = serializer.serialize_map(Some(len))?;
+
+        map.serialize_entry("@type", &object.class_name)?;
+        map.serialize_entry("@module", &object.module_uri)?;
+
+        for (name, value) in &object.properties {
+            map.serialize_entry(name, &WithTypeInfo(value))?;
+        }
+
+        for (key, value) in &object.entries {
+            map.serialize_entry(&WithTypeInfo(key), &WithTypeInfo(value))?;
+        }
+
+        for (i, value) in object.elements.iter().enumerate() {
+            map.serialize_entry(&i, &WithTypeInfo(value))?;
+        }
+
+        map.end()
+    }
+}
+
+#[cfg(feature = "json")]
+impl Serialize for WithTypeInfo<'_, Value> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Object(object) => WithTypeInfo(object).serialize(serializer),
+            Value::Pair(first, second) => {
+                serializer.collect_seq([WithTypeInfo(&**first), WithTypeInfo(&**second)])
+            }
+            Value::Array(values) | Value::Listing(values) | Value::Set(values) => {
+                serializer.collect_seq(values.iter().map(WithTypeInfo))
+            }
+            Value::Map(entries) | Value::Mapping(entries) if has_string_keys(entries) => serializer
+                .collect_map(
+                    entries
+                        .iter()
+                        .map(|(key, value)| (WithTypeInfo(key), WithTypeInfo(value))),
+                ),
+            Value::Map(entries) | Value::Mapping(entries) => serializer.collect_seq(
+                entries
+                    .iter()
+                    .map(|(key, value)| [WithTypeInfo(key), WithTypeInfo(value)]),
+            ),
+            value => value.serialize(serializer),
+        }
+    }
+}
+
+impl Object {
+    /// Iterates over the properties in the order pkl sent them.
+    pub fn iter_ordered(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.properties
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Converts the object's properties into a JSON map. With `type_info`, this and
+    /// every nested object also gets `@type` (the class name) and `@module` (the
+    /// module URI) keys so the original Pkl classes can be reconstructed later.
+    #[cfg(feature = "json")]
+    pub fn to_json_map(
+        &self,
+        type_info: bool,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, serde_json::Error> {
+        let value = if type_info {
+            serde_json::to_value(WithTypeInfo(self))?
+        } else {
+            serde_json::to_value(self)?
+        };
+
+        match value {
+            serde_json::Value::Object(map) => Ok(map),
+            // Objects with only elements serialize as sequences.
+            serde_json::Value::Array(elements) => Ok(elements
+                .into_iter()
+                .enumerate()
+                .map(|(i, element)| (i.to_string(), element))
+                .collect()),
+            _ => unreachable!("objects always serialize as maps or sequences"),
+        }
+    }
+}
+
+/// Converts into the JSON [`Value::write_json`] writes, which follows the
+/// [`Serialize`] implementation: durations and data sizes become strings such as
+/// `"5.min"`, regexes their pattern, classes and type aliases their name, bytes an
+/// array of numbers, int seqs `{"start", "end", "step"}` objects, pairs two-element
+/// arrays, and functions and non-finite floats `null`. Maps with non-string keys
+/// become arrays of `[key, value]` pairs. Object entry keys JSON has no key for,
+/// which make `write_json` fail, are replaced with their JSON text.
+#[cfg(feature = "json")]
+impl From<Value> for serde_json::Value {
+    fn from(value: Value) -> Self {
+        use serde_json::Value as Json;
+
+        fn key(key: Value) -> String {
+            match Json::from(key) {
+                Json::String(key) => key,
+                key => key.to_string(),
+            }
+        }
+
+        match value {
+            Value::Object(Object {
+                properties,
+                entries,
+                elements,
+                ..
+            }) => {
+                if properties.is_empty() && entries.is_empty() && !elements.is_empty() {
+                    return elements.into_iter().map(Json::from).collect();
+                }
+
+                let properties = properties
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()));
+                let entries = entries
+                    .into_iter()
+                    .map(|(name, value)| (key(name), value.into()));
+                let elements = elements
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, value)| (i.to_string(), value.into()));
+
+                Json::Object(properties.chain(entries).chain(elements).collect())
+            }
+            Value::Pair(first, second) => Json::Array(vec![(*first).into(), (*second).into()]),
+            Value::Array(values) | Value::Listing(values) | Value::Set(values) => {
+                values.into_iter().map(Json::from).collect()
+            }
+            Value::Map(entries) | Value::Mapping(entries) if has_string_keys(&entries) => {
+                Json::Object(
+                    entries
+                        .into_iter()
+                        .map(|(name, value)| (key(name), value.into()))
+                        .collect(),
+                )
+            }
+            Value::Map(entries) | Value::Mapping(entries) => entries
+                .into_iter()
+                .map(|(key, value)| Json::Array(vec![key.into(), value.into()]))
+                .collect(),
+            // Everything else serializes without nesting other values.
+            value => serde_json::to_value(value).expect("scalars always convert to JSON"),
+        }
+    }
+}
+
+impl Value {
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Int(_) => 2,
+            Value::Uint(_) => 3,
+            Value::Float(_) => 4,
+            Value::String(_) => 5,
+            Value::Bytes(_) => 6,
+            Value::Duration { .. } => 7,
+            Value::DataSize { .. } => 8,
+            Value::IntSeq { .. } => 9,
+            Value::Regex(_) => 10,
+            Value::Function => 11,
+            Value::Class { .. } => 12,
+            Value::TypeAlias { .. } => 13,
+            Value::Object(_) => 14,
+            Value::Pair(..) => 15,
+            Value::Array(_) => 16,
+            Value::Listing(_) => 17,
+            Value::Set(_) => 18,
+            Value::Map(_) => 19,
+            Value::Mapping(_) => 20,
+        }
+    }
+
+    /// Total order used by [`Value::canonicalize`].
+    ///
+    /// Numbers compare by value across `Int`, `Uint` and `Float`, with an integer
+    /// sorting right before a float of the same value and floats among themselves
+    /// ordered by [`f64::total_cmp`], so NaNs sort past either end. Values of other
+    /// variants are ordered by variant, in declaration order except that `Bool` sorts
+    /// right after `Null`. Within a variant, strings, regexes and bytes compare
+    /// bytewise, durations and data sizes compare by length and then unit, int
+    /// sequences by start, end and step, classes and typealiases by name and module
+    /// URI, objects compare by class name, module URI and then their properties, and
+    /// pairs, arrays, listings, sets and maps compare their elements/entries lexicographically.
+    pub fn canonical_cmp(&self, other: &Value) -> Ordering {
+        if let Some(ordering) = cmp_numbers(self, other) {
+            return ordering;
+        }
+
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::String(a), Value::String(b)) | (Value::Regex(a), Value::Regex(b)) => a.cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+            (
+                Value::Duration { value, unit },
+                Value::Duration {
+                    value: other_value,
+                    unit: other_unit,
+                },
+            ) => (value * unit.seconds())
+                .total_cmp(&(other_value * other_unit.seconds()))
+                .then_with(|| unit.seconds().total_cmp(&other_unit.seconds())),
+            (
+                Value::DataSize { value, unit },
+                Value::DataSize {
+                    value: other_value,
+                    unit: other_unit,
+                },
+            ) => (value * unit.bytes() as f64)
+                .total_cmp(&(other_value * other_unit.bytes() as f64))
+                .then_with(|| unit.bytes().cmp(&other_unit.bytes())),
+            (Value::Object(a), Value::Object(b)) => a
+                .class_name
+                .cmp(&b.class_name)
+                .then_with(|| a.module_uri.cmp(&b.module_uri))
+                .then_with(|| {
+                    cmp_seq(&a.properties, &b.properties, |(ka, va), (kb, vb)| {
+                        ka.cmp(kb).then_with(|| va.canonical_cmp(vb))
+                    })
+                })
+                .then_with(|| {
+                    cmp_seq(&a.entries, &b.entries, |(ka, va), (kb, vb)| {
+                        ka.canonical_cmp(kb).then_with(|| va.canonical_cmp(vb))
+                    })
+                })
+                .then_with(|| cmp_seq(&a.elements, &b.elements, Value::canonical_cmp)),
+            (
+                Value::IntSeq { start, end, step },
+                Value::IntSeq {
+                    start: other_start,
+                    end: other_end,
+                    step: other_step,
+                },
+            ) => (start, end, step).cmp(&(other_start, other_end, other_step)),
+            (
+                Value::Class { name, module_uri },
+                Value::Class {
+                    name: other_name,
+                    module_uri: other_module_uri,
+                },
+            )
+            | (
+                Value::TypeAlias { name, module_uri },
+                Value::TypeAlias {
+                    name: other_name,
+                    module_uri: other_module_uri,
+                },
+            ) => (name, module_uri).cmp(&(other_name, other_module_uri)),
+            (Value::Pair(a, b), Value::Pair(c, d)) => {
+                a.canonical_cmp(c).then_with(|| b.canonical_cmp(d))
+            }
+            (Value::Array(a), Value::Array(b))
+            | (Value::Listing(a), Value::Listing(b))
+            | (Value::Set(a), Value::Set(b)) => cmp_seq(a, b, Value::canonical_cmp),
+            (Value::Map(a), Value::Map(b)) | (Value::Mapping(a), Value::Mapping(b)) => {
+                cmp_seq(a, b, |(ka, va), (kb, vb)| {
+                    ka.canonical_cmp(kb).then_with(|| va.canonical_cmp(vb))
+                })
+            }
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+
+    /// Recursively sorts map and mapping entries by key and set elements using
+    /// [`Value::canonical_cmp`], and object properties by name, so that two equal
+    /// configs always produce the same tree.
+    pub fn canonicalize(&mut self) {
+        match self {
+            Value::Object(object) => {
+                object.properties.values_mut().for_each(Value::canonicalize);
+                object.properties.sort_keys();
+                canonicalize_entries(&mut object.entries);
+                object.elements.iter_mut().for_each(Value::canonicalize);
+            }
+            Value::Pair(first, second) => {
+                first.canonicalize();
+                second.canonicalize();
+            }
+            Value::Array(values) | Value::Listing(values) => {
+                values.iter_mut().for_each(Value::canonicalize)
+            }
+            Value::Set(values) => {
+                values.iter_mut().for_each(Value::canonicalize);
+                values.sort_by(Value::canonical_cmp);
+            }
+            Value::Map(entries) | Value::Mapping(entries) => canonicalize_entries(entries),
+            _ => {}
+        }
+    }
+
+    /// Keeps only the object properties, and the map/mapping entries with string
+    /// keys, for which `f` returns `true`. Entries with non-string keys are kept;
+    /// use [`Value::retain_entries`] for those. Does nothing on other variants.
+    pub fn retain(&mut self, mut f: impl FnMut(&str, &Value) -> bool) {
+        match self {
+            Value::Object(object) => object.properties.retain(|name, value| f(name, value)),
+            Value::Map(entries) | Value::Mapping(entries) => {
+                entries.retain(|(key, value)| match key {
+                    Value::String(key) => f(key, value),
+                    _ => true,
+                })
+            }
+            _ => {}
+        }
+    }
+
+    /// Keeps only the map/mapping entries for which `f` returns `true`. Does
+    /// nothing on other variants.
+    pub fn retain_entries(&mut self, mut f: impl FnMut(&Value, &Value) -> bool) {
+        if let Value::Map(entries) | Value::Mapping(entries) = self {
+            entries.retain(|(key, value)| f(key, value));
+        }
+    }
+
+    /// Lazily yields the integers of an `IntSeq` from `start` towards `end`
+    /// (inclusive) in increments of `step`, which may be negative for descending
+    /// sequences. A sequence whose step points away from `end` is empty.
+    pub fn iter_ints(&self) -> Result<impl Iterator<Item = i64> + use<>, ValueError> {
+        let Value::IntSeq { start, end, step } = *self else {
+            return Err(ValueError::UnexpectedValue);
+        };
+
+        if step == 0 {
+            return Err(ValueError::ZeroStep);
+        }
+
+        Ok(iter::successors(Some(start), move |n| n.checked_add(step))
+            .take_while(move |n| if step > 0 { *n <= end } else { *n >= end }))
+    }
+
+    /// Compiles a `Regex` value. `None` for other variants and for patterns using
+    /// Java regex features the `regex` crate doesn't support, such as lookaround and
+    /// backreferences.
+    #[cfg(feature = "regex")]
+    pub fn compile_regex(&self) -> Option<regex::Regex> {
+        match self {
+            Value::Regex(pattern) => regex::Regex::new(pattern).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_pair(&self) -> Option<(&Value, &Value)> {
+        match self {
+            Value::Pair(first, second) => Some((first, second)),
+            _ => None,
+        }
+    }
+
+    pub fn as_set(&self) -> Option<&[Value]> {
+        match self {
+            Value::Set(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Like [`Value::as_bytes`], but also returns the UTF-8 encoding of a string, for
+    /// binary data that was read as text, e.g. through `read(...).text`.
+    pub fn as_bytes_utf8(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            Value::String(s) => Some(s.as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// Number of bytes in a `DataSize`, honouring binary (`mib`) versus decimal (`mb`)
+    /// units and rounding fractional sizes such as `1.5.kb` to the nearest byte.
+    /// `None` for other variants and for negative or out-of-range sizes.
+    pub fn as_byte_count(&self) -> Option<u64> {
+        let Value::DataSize { value, unit } = self else {
+            return None;
+        };
+
+        let bytes = (value * unit.bytes() as f64).round();
+
+        (0.0..=u64::MAX as f64)
+            .contains(&bytes)
+            .then_some(bytes as u64)
+    }
+
+    /// Looks up `key` in a `Map` or `Mapping` by structural equality, so keys that
+    /// are objects, pairs or collections can be found with an equal [`Value`].
+    /// Integer keys match whether they were decoded as `Int` or `Uint`. `None` for
+    /// other variants and missing keys.
+    pub fn map_get(&self, key: &Value) -> Option<&Value> {
+        let (Value::Map(entries) | Value::Mapping(entries)) = self else {
+            return None;
+        };
+
+        entries
+            .iter()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Converts a `Map` or `Mapping` with string keys, such as a Pkl
+    /// `Mapping<String, ServerConfig>`, into a `HashMap` by converting every value
+    /// into `T`.
+    pub fn into_typed_map<T>(self) -> Result<HashMap<String, T>, Error>
+    where
+        T: TryFrom<Value>,
+        T::Error: Into<Error>,
+    {
+        let (Value::Map(entries) | Value::Mapping(entries)) = self else {
+            return Err(ValueError::UnexpectedValue.into());
+        };
+
+        entries
+            .into_iter()
+            .map(|(key, value)| {
+                let key = String::try_from(key)?;
+                let value = T::try_from(value).map_err(Into::into)?;
+
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    /// Flattens the tree into its leaves, keyed by path, so two configs can be diffed
+    /// entry by entry.
+    ///
+    /// Path segments are joined with `.`: object properties and string map and entry
+    /// keys by name, pair elements as `first` and `second`, array, set and object
+    /// elements by index, integer and boolean keys as written and other keys by their
+    /// `Debug` form in brackets, so `server.ports.0` is the first port of the `server`
+    /// property. Names escape `.` and `\` with a `\`, and names that would read as an
+    /// index or another key, like `"0"` or `"true"`, start with a `\`, so different
+    /// leaves get different paths. A scalar at the root has the empty path. Empty
+    /// objects, arrays, sets and maps are kept as leaves so they still show up in a
+    /// diff.
+    pub fn flatten(&self) -> BTreeMap<String, Value> {
+        let mut leaves = BTreeMap::new();
+        self.flatten_into(String::new(), &mut leaves);
+        leaves
+    }
+
+    fn flatten_into(&self, path: String, leaves: &mut BTreeMap<String, Value>) {
+        let join = |segment: &dyn std::fmt::Display| match path.as_str() {
+            "" => segment.to_string(),
+            path => format!("{path}.{segment}"),
+        };
+
+        match self {
+            Value::Object(object)
+                if !object.properties.is_empty()
+                    || !object.entries.is_empty()
+                    || !object.elements.is_empty() =>
+            {
+                for (name, value) in &object.properties {
+                    value.flatten_into(join(&name_segment(name)), leaves);
+                }
+
+                for (key, value) in &object.entries {
+                    value.flatten_into(join(&key_segment(key)), leaves);
+                }
+
+                for (i, value) in object.elements.iter().enumerate() {
+                    value.flatten_into(join(&i), leaves);
+                }
+            }
+            Value::Pair(first, second) => {
+                first.flatten_into(join(&"first"), leaves);
+                second.flatten_into(join(&"second"), leaves);
+            }
+            Value::Array(values) | Value::Listing(values) | Value::Set(values)
+                if !values.is_empty() =>
+            {
+                for (i, value) in values.iter().enumerate() {
+                    value.flatten_into(join(&i), leaves);
+                }
+            }
+            Value::Map(entries) | Value::Mapping(entries) if !entries.is_empty() => {
+                for (key, value) in entries {
+                    value.flatten_into(join(&key_segment(key)), leaves);
+                }
+            }
+            value => {
+                leaves.insert(path, value.clone());
+            }
+        }
+    }
+
+    #[cfg(feature = "json")]
+    pub fn write_json(&self, writer: impl Write, pretty: bool) -> Result<(), serde_json::Error> {
+        if pretty {
+            serde_json::to_writer_pretty(writer, self)
+        } else {
+            serde_json::to_writer(writer, self)
+        }
+    }
+
+    /// Writes the value as YAML. serde_yaml only writes block style, so `pretty`
+    /// has no effect and is there to match [`Value::write_json`]. Fails on bytes,
+    /// which serde_yaml doesn't support.
+    #[cfg(feature = "yaml")]
+    pub fn write_yaml(&self, writer: impl Write, pretty: bool) -> Result<(), serde_yaml::Error> {
+        let _ = pretty;
+        serde_yaml::to_writer(writer, self)
+    }
+
+    /// Fails on `null` and on maps with non-string keys, neither of which TOML
+    /// can represent.
+    #[cfg(feature = "toml")]
+    pub fn to_toml_string(&self, pretty: bool) -> Result<String, toml::ser::Error> {
+        if pretty {
+            toml::to_string_pretty(self)
+        } else {
+            toml::to_string(self)
+        }
+    }
+}
+
+fn has_string_keys(entries: &[(Value, Value)]) -> bool {
+    entries
+        .iter()
+        .all(|(key, _)| matches!(key, Value::String(_)))
+}
+
+/// Canonicalizes keys and values, then sorts the entries by key.
+fn canonicalize_entries(entries: &mut [(Value, Value)]) {
+    for (key, value) in entries.iter_mut() {
+        key.canonicalize();
+        value.canonicalize();
+    }
+
+    entries.sort_by(|(a, _), (b, _)| a.canonical_cmp(b));
+}
+
+/// Path segment of a map key in [`Value::flatten`].
+pub(crate) fn key_segment(key: &Value) -> String {
+    match key {
+        Value::String(key) => name_segment(key),
+        Value::Int(key) => key.to_string(),
+        Value::Uint(key) => key.to_string(),
+        Value::Bool(key) => key.to_string(),
+        key => format!("[{key:?}]"),
+    }
+}
+
+/// Path segment of a property name or string key in [`Value::flatten`]. Escapes the
+/// name so it can't be mistaken for a path of several segments or for the segment of
+/// an element or a key of another type.
+pub(crate) fn name_segment(name: &str) -> String {
+    let reads_as_other =
+        name.parse::<i128>().is_ok() || matches!(name, "true" | "false") || name.starts_with('[');
+    let mut segment = String::with_capacity(name.len() + 1);
+
+    if reads_as_other {
+        segment.push('\\');
+    }
+
+    for c in name.chars() {
+        if matches!(c, '.' | '\\') {
+            segment.push('\\');
+        }
+        segment.push(c);
+    }
+
+    segment
+}
+
+/// Orders numbers by value for [`Value::canonical_cmp`], or `None` unless both `a`
+/// and `b` are numbers.
+fn cmp_numbers(a: &Value, b: &Value) -> Option<Ordering> {
+    let integer = |value: &Value| match value {
+        Value::Int(i) => Some(i128::from(*i)),
+        Value::Uint(u) => Some(i128::from(*u)),
+        _ => None,
+    };
+
+    let ordering = match (a, b) {
+        (Value::Float(a), Value::Float(b)) => a.total_cmp(b),
+        (Value::Float(float), int) => cmp_int_float(integer(int)?, *float)
+            .then(Ordering::Less)
+            .reverse(),
+        (int, Value::Float(float)) => cmp_int_float(integer(int)?, *float).then(Ordering::Less),
+        (a, b) => integer(a)?.cmp(&integer(b)?),
+    };
+
+    Some(ordering)
+}
+
+/// Compares `int` with `float` exactly, without rounding `int` to a float. NaNs are
+/// past either end like in [`f64::total_cmp`].
+fn cmp_int_float(int: i128, float: f64) -> Ordering {
+    if float.is_nan() {
+        return if float.is_sign_negative() {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        };
+    }
+
+    // The cast saturates, which keeps infinities and floats beyond any i64 or u64 in
+    // order.
+    let whole = float.trunc();
+    int.cmp(&(whole as i128))
+        .then_with(|| 0f64.total_cmp(&(float - whole)))
+}
+
+fn cmp_seq<T>(
+    a: impl IntoIterator<Item = T>,
+    b: impl IntoIterator<Item = T>,
+    mut cmp: impl FnMut(T, T) -> Ordering,
+) -> Ordering {
+    let (mut a, mut b) = (a.into_iter(), b.into_iter());
+
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => match cmp(x, y) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            },
+        }
+    }
+}
+
 impl TryFrom<Value> for String {
     type Error = ValueError;
 
@@ -38,6 +1072,80 @@ impl TryFrom<Value> for String {
     }
 }
 
+impl TryFrom<Value> for Vec<u8> {
+    type Error = ValueError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => Err(ValueError::UnexpectedValue),
+        }
+    }
+}
+
+impl<A, B> TryFrom<Value> for (A, B)
+where
+    A: TryFrom<Value>,
+    A::Error: Into<Error>,
+    B: TryFrom<Value>,
+    B::Error: Into<Error>,
+{
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Pair(first, second) => Ok((
+                A::try_from(*first).map_err(Into::into)?,
+                B::try_from(*second).map_err(Into::into)?,
+            )),
+            _ => Err(ValueError::UnexpectedValue.into()),
+        }
+    }
+}
+
+impl<T> TryFrom<Value> for HashSet<T>
+where
+    T: TryFrom<Value> + Eq + Hash,
+    T::Error: Into<Error>,
+{
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Set(values) => values
+                .into_iter()
+                .map(|value| T::try_from(value).map_err(Into::into))
+                .collect(),
+            _ => Err(ValueError::UnexpectedValue.into()),
+        }
+    }
+}
+
+/// Fails on negative durations, which `std::time::Duration` can't represent.
+impl TryFrom<Value> for Duration {
+    type Error = ValueError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Duration { value, unit } => Duration::try_from_secs_f64(value * unit.seconds())
+                .map_err(|_| ValueError::UnexpectedValue),
+            _ => Err(ValueError::UnexpectedValue),
+        }
+    }
+}
+
+impl TryFrom<Value> for u64 {
+    type Error = ValueError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Uint(n) => Ok(n),
+            Value::Int(n) => n.try_into().map_err(|_| ValueError::UnexpectedValue),
+            _ => Err(ValueError::UnexpectedValue),
+        }
+    }
+}
+
 pub enum Response {
     CreateEvaluator(CreateEvaluatorResponse),
     Evaluate(EvaluateResponse),
@@ -59,6 +1167,21 @@ macro_rules! impl_from {
                     $(Response::$name(_) => stringify!($name),)+
                 }
             }
+
+            /// Id of the client request this answers, for responses to client requests.
+            pub fn request_id(&self) -> Option<u64> {
+                match self {
+                    Response::CreateEvaluator(response) => Some(response.request_id),
+                    Response::Evaluate(response) => Some(response.request_id),
+                    _ => None,
+                }
+            }
+
+            pub fn code(&self) -> u64 {
+                match self {
+                    $(Response::$name(_) => <$ty as Message>::CODE,)+
+                }
+            }
         }
 
         $(
@@ -112,7 +1235,7 @@ impl Message for CreateEvaluatorResponse {
 }
 
 #[skip_serializing_none]
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EvaluateResponse {
     pub request_id: u64,
@@ -212,7 +1335,7 @@ impl Message for InitializeResourceReaderRequest {
     const CODE: u64 = 0x30;
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CloseExternalProcess {
     // This message has no properties according to the documentation
@@ -221,3 +1344,318 @@ pub struct CloseExternalProcess {
 impl Message for CloseExternalProcess {
     const CODE: u64 = 0x32;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct ServerConfig {
+        host: String,
+        port: u64,
+    }
+
+    impl TryFrom<Value> for ServerConfig {
+        type Error = Error;
+
+        fn try_from(value: Value) -> Result<Self, Error> {
+            let Value::Object(mut object) = value else {
+                return Err(ValueError::UnexpectedValue.into());
+            };
+            let mut take = |name| {
+                object
+                    .properties
+                    .shift_remove(name)
+                    .ok_or(ValueError::UnexpectedValue)
+            };
+
+            Ok(ServerConfig {
+                host: take("host")?.try_into()?,
+                port: take("port")?.try_into()?,
+            })
+        }
+    }
+
+    fn server(host: &str, port: u64) -> Value {
+        crate::value!(ServerConfig @ "file:///servers.pkl" { host => host, port => port })
+    }
+
+    /// A Pkl `Mapping<String, ServerConfig>` with three servers.
+    fn servers() -> Value {
+        Value::Mapping(vec![
+            (Value::from("web"), server("web.internal", 80)),
+            (Value::from("api"), server("api.internal", 8080)),
+            (Value::from("db"), server("db.internal", 5432)),
+        ])
+    }
+
+    fn expected() -> HashMap<String, ServerConfig> {
+        HashMap::from([
+            (
+                "web".to_string(),
+                ServerConfig {
+                    host: "web.internal".to_string(),
+                    port: 80,
+                },
+            ),
+            (
+                "api".to_string(),
+                ServerConfig {
+                    host: "api.internal".to_string(),
+                    port: 8080,
+                },
+            ),
+            (
+                "db".to_string(),
+                ServerConfig {
+                    host: "db.internal".to_string(),
+                    port: 5432,
+                },
+            ),
+        ])
+    }
+
+    #[test]
+    fn mapping_of_objects_converts_into_typed_map() {
+        assert_eq!(
+            servers().into_typed_map::<ServerConfig>().unwrap(),
+            expected()
+        );
+    }
+
+    #[test]
+    fn mapping_of_objects_deserializes_into_hash_map() {
+        let servers = servers();
+
+        assert_eq!(
+            HashMap::<String, ServerConfig>::deserialize(&servers).unwrap(),
+            expected()
+        );
+    }
+
+    #[test]
+    fn numbers_compare_by_value_across_variants() {
+        let mut numbers = vec![
+            Value::Float(f64::NAN),
+            Value::Uint(u64::MAX),
+            Value::Float(2.5),
+            Value::Uint(2),
+            Value::Float(f64::NEG_INFINITY),
+            Value::Float(2.0),
+            Value::Int(-3),
+            Value::Float(-2.5),
+            Value::Float(f64::INFINITY),
+            Value::Int(i64::MIN),
+        ];
+        numbers.sort_by(Value::canonical_cmp);
+
+        assert_eq!(
+            numbers
+                .iter()
+                .map(|number| format!("{number:?}"))
+                .collect::<Vec<_>>(),
+            [
+                "Float(-inf)",
+                "Int(-9223372036854775808)",
+                "Int(-3)",
+                "Float(-2.5)",
+                "Uint(2)",
+                "Float(2.0)",
+                "Float(2.5)",
+                "Uint(18446744073709551615)",
+                "Float(inf)",
+                "Float(NaN)",
+            ]
+        );
+    }
+
+    #[test]
+    fn equal_integers_compare_equal_whatever_their_encoding() {
+        assert_eq!(
+            Value::Int(7).canonical_cmp(&Value::Uint(7)),
+            Ordering::Equal
+        );
+        assert_eq!(
+            Value::Int(-1).canonical_cmp(&Value::Uint(0)),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn integers_beyond_float_precision_compare_exactly() {
+        let big = 1u64 << 53;
+
+        assert_eq!(
+            Value::Uint(big + 1).canonical_cmp(&Value::Float(big as f64)),
+            Ordering::Greater
+        );
+        assert_eq!(
+            Value::Float(big as f64).canonical_cmp(&Value::Uint(big + 1)),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn value_macro_builds_objects() {
+        let expected = Object {
+            class_name: "App".to_string(),
+            module_uri: String::new(),
+            properties: IndexMap::from([
+                ("name".to_string(), Value::from("demo")),
+                ("ports".to_string(), Value::Array(vec![Value::from(80)])),
+                (
+                    "db".to_string(),
+                    Value::Object(Object {
+                        class_name: "Db".to_string(),
+                        module_uri: "file:///app.pkl".to_string(),
+                        properties: IndexMap::from([("host".to_string(), Value::Null)]),
+                        entries: vec![],
+                        elements: vec![],
+                    }),
+                ),
+            ]),
+            entries: vec![],
+            elements: vec![],
+        };
+
+        assert_eq!(
+            crate::value!(App {
+                name => "demo",
+                ports => [80],
+                db => (crate::value!(Db @ "file:///app.pkl" { host => null })),
+            }),
+            Value::Object(expected)
+        );
+    }
+
+    /// One value of each kind that JSON has no direct counterpart for.
+    #[cfg(feature = "json")]
+    fn non_json_values() -> Value {
+        crate::value!({
+            "duration" => (Value::Duration { value: 5.0, unit: DurationUnit::Minutes }),
+            "regex" => (Value::Regex("a+".to_string())),
+            "function" => (Value::Function),
+            "bytes" => (Value::Bytes(vec![1, 2])),
+            "intSeq" => (Value::IntSeq { start: 0, end: 10, step: 2 }),
+            "nan" => (f64::NAN),
+            "pair" => (Value::Pair(Box::new(Value::from(1)), Box::new(Value::from("a")))),
+            "intKeys" => (Value::Mapping(vec![(Value::from(1), Value::from("one"))])),
+            "object" => (crate::value!(Db { host => "db.internal" })),
+        })
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_conversion_matches_write_json() {
+        let value = non_json_values();
+        let mut written = Vec::new();
+        value.write_json(&mut written, false).unwrap();
+
+        let converted = serde_json::Value::from(value);
+
+        assert_eq!(
+            converted,
+            serde_json::from_slice::<serde_json::Value>(&written).unwrap()
+        );
+        assert_eq!(
+            converted,
+            serde_json::json!({
+                "duration": "5.min",
+                "regex": "a+",
+                "function": null,
+                "bytes": [1, 2],
+                "intSeq": { "start": 0, "end": 10, "step": 2 },
+                "nan": null,
+                "pair": [1, "a"],
+                "intKeys": [[1, "one"]],
+                "object": { "host": "db.internal" },
+            })
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn object_entries_keyed_by_collections_convert_to_json() {
+        let value = Value::Object(Object {
+            class_name: "Dynamic".to_string(),
+            module_uri: String::new(),
+            properties: IndexMap::new(),
+            entries: vec![
+                (crate::value!([1, 2]), Value::from("list")),
+                (Value::from(3), Value::from("int")),
+            ],
+            elements: vec![],
+        });
+
+        assert!(value.write_json(Vec::new(), false).is_err());
+        assert_eq!(
+            serde_json::Value::from(value),
+            serde_json::json!({ "[1,2]": "list", "3": "int" })
+        );
+    }
+
+    #[test]
+    fn flattened_paths_keep_distinct_leaves_apart() {
+        let value = Value::Object(Object {
+            class_name: "Dynamic".to_string(),
+            module_uri: String::new(),
+            properties: IndexMap::from([
+                ("a.b".to_string(), Value::from(1)),
+                ("a".to_string(), crate::value!({ "b" => 2, "c\\" => 3 })),
+            ]),
+            entries: vec![
+                (Value::from("0"), Value::from(4)),
+                (Value::Null, Value::from(5)),
+            ],
+            elements: vec![Value::from(6)],
+        });
+
+        let paths: Vec<_> = value.flatten().into_keys().collect();
+
+        assert_eq!(paths, ["0", "[Null]", "\\0", "a.b", "a.c\\\\", "a\\.b"]);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn written_json_reads_back() {
+        for pretty in [false, true] {
+            let mut written = Vec::new();
+            servers().write_json(&mut written, pretty).unwrap();
+
+            let read: HashMap<String, ServerConfig> = serde_json::from_slice(&written).unwrap();
+            assert_eq!(read, expected(), "pretty: {pretty}");
+        }
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn written_yaml_reads_back() {
+        for pretty in [false, true] {
+            let mut written = Vec::new();
+            servers().write_yaml(&mut written, pretty).unwrap();
+
+            let read: HashMap<String, ServerConfig> = serde_yaml::from_slice(&written).unwrap();
+            assert_eq!(read, expected(), "pretty: {pretty}");
+        }
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_is_the_same_whether_pretty_or_not() {
+        let value = crate::value!([(f64::INFINITY), (f64::NAN)]);
+        let mut compact = Vec::new();
+        let mut pretty = Vec::new();
+        value.write_yaml(&mut compact, false).unwrap();
+        value.write_yaml(&mut pretty, true).unwrap();
+
+        let (infinity, nan): (f64, f64) = serde_yaml::from_slice(&compact).unwrap();
+
+        assert_eq!(compact, pretty);
+        assert_eq!(infinity, f64::INFINITY);
+        assert!(nan.is_nan());
+        for pretty in [false, true] {
+            let bytes = Value::Bytes(vec![1, 2]);
+            assert!(bytes.write_yaml(Vec::new(), pretty).is_err());
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use serde::de::DeserializeOwned;
+use tracing::instrument;
+
+use crate::{
+    client::Uri,
+    errors::Error,
+    evaluator::{EvalOpts, Evaluator},
+    server::Value,
+};
+
+/// An evaluator created once inside the pkl process and reused for every
+/// evaluation, created with [`Evaluator::create_session`].
+///
+/// Allow-lists, readers, the output format and the other create-time options are
+/// fixed when the evaluator is created. [`Session::eval_with`] covers the odd call
+/// that needs different ones.
+///
+/// Dropping a session closes its evaluator too; use [`Session::close`] to find out
+/// whether that succeeded.
+pub struct Session<'a> {
+    evaluator: &'a mut Evaluator,
+    opts: EvalOpts,
+    evaluator_id: i64,
+    closed: bool,
+}
+
+impl<'a> Session<'a> {
+    pub(crate) fn new(evaluator: &'a mut Evaluator, opts: EvalOpts, evaluator_id: i64) -> Self {
+        Self {
+            evaluator,
+            opts,
+            evaluator_id,
+            closed: false,
+        }
+    }
+
+    #[instrument(skip(self), fields(evaluator_id = self.evaluator_id))]
+    pub fn eval(&mut self, uri: Uri) -> Result<Option<Value>, Error> {
+        self.evaluator
+            .evaluate(&self.opts, self.evaluator_id, uri, None, None)
+    }
+
+    #[instrument(skip(self), fields(evaluator_id = self.evaluator_id))]
+    pub fn eval_expr(&mut self, uri: Uri, expr: &str) -> Result<Option<Value>, Error> {
+        self.evaluator
+            .evaluate(&self.opts, self.evaluator_id, uri, None, Some(expr))
+    }
+
+    /// Like [`Session::eval_expr`], but deserializes the result into `T`, like
+    /// [`Evaluator::eval_into`].
+    pub fn eval_expr_into<T: DeserializeOwned>(
+        &mut self,
+        uri: Uri,
+        expr: &str,
+    ) -> Result<T, Error> {
+        let value = self.eval_expr(uri, expr)?.ok_or(Error::EmptyResult)?;
+        Ok(T::deserialize(&value)?)
+    }
+
+    /// Evaluates `uri` with `opts` instead of the session's options. When they
+    /// differ in create-time options or reader instances, a secondary evaluator is
+    /// created in the same pkl process for this call and closed again afterwards.
+    #[instrument(skip(self, opts), fields(evaluator_id = self.evaluator_id))]
+    pub fn eval_with(&mut self, opts: &EvalOpts, uri: Uri) -> Result<Option<Value>, Error> {
+        if opts.create_options_hash() == self.opts.create_options_hash()
+            && opts.same_readers(&self.opts)
+        {
+            return self
+                .evaluator
+                .evaluate(opts, self.evaluator_id, uri, None, None);
+        }
+
+        let evaluator_id = self.evaluator.create_evaluator(opts, None)?;
+        let result = self.evaluator.evaluate(opts, evaluator_id, uri, None, None);
+        self.evaluator.close_evaluator(evaluator_id)?;
+
+        result
+    }
+
+    pub fn close(mut self) -> Result<(), Error> {
+        self.closed = true;
+        self.evaluator.close_evaluator(self.evaluator_id)
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.evaluator.close_evaluator(self.evaluator_id);
+        }
+    }
+}
+
+/// Declares a struct whose fields are each filled by evaluating a Pkl expression
+/// against a module, for pulling several typed values out of one module.
+///
+/// The macro generates the struct and a `query(session, uri)` function that
+/// evaluates every expression with [`Session::eval_expr_into`] in the session's
+/// evaluator, so each field type only needs to implement `Deserialize`.
+///
+/// ```no_run
+/// use rust_pkl::{EvalOpts, Evaluator, Protocol, Uri, pkl_query};
+///
+/// pkl_query! {
+///     #[derive(Debug)]
+///     pub struct Server {
+///         host: String = "server.host",
+///         port: u64 = "server.port",
+///     }
+/// }
+///
+/// # fn main() -> Result<(), rust_pkl::Error> {
+/// let mut evaluator = Evaluator::new(Protocol::new()?);
+/// let opts = EvalOpts::default().allowing_module("file:");
+/// let mut session = evaluator.create_session(&opts)?;
+/// let server = Server::query(&mut session, &Uri::File("/etc/app.pkl".into()))?;
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! pkl_query {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field:ident: $ty:ty = $expr:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $vis $field: $ty,)*
+        }
+
+        impl $name {
+            $vis fn query(
+                session: &mut $crate::session::Session<'_>,
+                uri: &$crate::client::Uri,
+            ) -> ::std::result::Result<Self, $crate::errors::Error> {
+                ::std::result::Result::Ok(Self {
+                    $($field: session.eval_expr_into(uri.clone(), $expr)?,)*
+                })
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        client::{CloseEvaluator, CreateEvaluatorRequest, EvaluateRequest, Uri},
+        errors::Error,
+        evaluator::{EvalOpts, Evaluator},
+        mock::{self, MockProtocol},
+        protocol::Message,
+        reader::ResourceReader,
+        server::{CreateEvaluatorResponse, EvaluateResponse, Value},
+        value,
+    };
+
+    pkl_query! {
+        struct Server {
+            host: String = "server.host",
+            port: u16 = "server.port",
+            tags: Vec<String> = "server.tags",
+        }
+    }
+
+    fn created(request_id: u64, evaluator_id: i64) -> Vec<u8> {
+        let created = value!({ "requestId" => request_id, "evaluatorId" => evaluator_id });
+        mock::message(CreateEvaluatorResponse::CODE, created).unwrap()
+    }
+
+    fn evaluated(request_id: u64, evaluator_id: i64, result: &[u8]) -> Vec<u8> {
+        let result = Value::Bytes(result.to_vec());
+        let evaluated = value!({
+            "requestId" => request_id,
+            "evaluatorId" => evaluator_id,
+            "result" => result,
+        });
+        mock::message(EvaluateResponse::CODE, evaluated).unwrap()
+    }
+
+    /// Server output creating evaluator 1 and answering the following requests with
+    /// `results`, each a value encoded as pkl does.
+    fn session(results: &[Vec<u8>]) -> Vec<u8> {
+        let mut transcript = created(0, 1);
+
+        for (request_id, result) in (1..).zip(results) {
+            transcript.extend(evaluated(request_id, 1, result));
+        }
+
+        transcript
+    }
+
+    struct Vault;
+
+    impl ResourceReader for Vault {
+        fn scheme(&self) -> &str {
+            "vault"
+        }
+
+        fn read(&self, _uri: &str) -> Result<Vec<u8>, String> {
+            Ok(b"secret".to_vec())
+        }
+    }
+
+    fn with_vault(vault: &Arc<Vault>) -> EvalOpts {
+        EvalOpts {
+            allowed_resources: vec!["vault:".to_string()],
+            client_resource_readers: vec![vault.clone()],
+            ..EvalOpts::default()
+        }
+    }
+
+    #[test]
+    fn query_deserializes_each_expression() {
+        let (proto, _mock) = MockProtocol::new(session(&[
+            rmp_serde::to_vec("localhost").unwrap(),
+            rmp_serde::to_vec(&8080).unwrap(),
+            rmp_serde::to_vec(&(0x5, ["web", "prod"])).unwrap(),
+        ]));
+        let mut evaluator = Evaluator::new(proto);
+        let mut session = evaluator.create_session(&EvalOpts::default()).unwrap();
+
+        let server = Server::query(&mut session, &Uri::File("/app.pkl".into())).unwrap();
+
+        assert_eq!(server.host, "localhost");
+        assert_eq!(server.port, 8080);
+        assert_eq!(server.tags, ["web", "prod"]);
+    }
+
+    #[test]
+    fn query_reports_values_of_the_wrong_type() {
+        let (proto, _mock) = MockProtocol::new(session(&[
+            rmp_serde::to_vec("localhost").unwrap(),
+            rmp_serde::to_vec("eighty").unwrap(),
+        ]));
+        let mut evaluator = Evaluator::new(proto);
+        let mut session = evaluator.create_session(&EvalOpts::default()).unwrap();
+
+        let err = Server::query(&mut session, &Uri::File("/app.pkl".into()));
+
+        assert!(matches!(err, Err(Error::Value(_))));
+    }
+
+    #[test]
+    fn eval_with_reuses_the_evaluator_only_for_the_same_readers() {
+        let mut transcript = session(&[b"\x01".to_vec()]);
+        transcript.extend(created(2, 2));
+        transcript.extend(evaluated(3, 2, b"\x02"));
+        let (proto, mock) = MockProtocol::new(transcript);
+        let mut evaluator = Evaluator::new(proto);
+        let vault = Arc::new(Vault);
+        let mut session = evaluator.create_session(&with_vault(&vault)).unwrap();
+
+        let same = session.eval_with(&with_vault(&vault), Uri::default());
+        let other = session.eval_with(&with_vault(&Arc::new(Vault)), Uri::default());
+
+        assert_eq!(same.unwrap(), Some(value!(1)));
+        assert_eq!(other.unwrap(), Some(value!(2)));
+        assert_eq!(
+            mock.sent_codes().unwrap(),
+            [
+                CreateEvaluatorRequest::CODE,
+                EvaluateRequest::CODE,
+                CreateEvaluatorRequest::CODE,
+                EvaluateRequest::CODE,
+                CloseEvaluator::CODE,
+            ]
+        );
+    }
+}